@@ -1,41 +1,166 @@
 use crossbeam::atomic::AtomicCell;
-use filesystemtable::{FsTable, FsIngester};
+use filesystemtable::{FsTable, FsIngester, TableEntry};
 use rayon::iter::{ParallelBridge, ParallelIterator};
 use rayon::prelude::*;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt;
-use std::io::Read;
-use std::path::{Path, PathBuf};
-use std::{cmp::Ordering, fs::File, io, iter::Scan};
-use thiserror::Error;
+use std::io::{self, Read};
+use std::num::NonZeroU128;
+use std::path::{Component, Path, PathBuf};
+use std::time::Instant;
+use std::{cmp::Ordering, fs::File, iter::Scan};
+
+pub mod persistence;
+
+/// The names most users need, importable with a single
+/// `use structureddedup::prelude::*;`
+pub mod prelude {
+    pub use crate::persistence::EntriesFileError;
+    pub use crate::{Dedup, DedupBuilder, Inventory};
+    pub use filesystemtable::{FsIngester, FsTable, TableEntry};
+}
 
 pub struct DedupBuilder {
     root: PathBuf,
+    additional_roots: Vec<(String, PathBuf)>,
     digest_file: Option<PathBuf>,
+    compute_digests: bool,
+    trust_cache_when_unchanged: bool,
 }
 
 impl DedupBuilder {
     pub fn new<P: AsRef<Path>>(root: P) -> DedupBuilder {
         DedupBuilder {
             root: root.as_ref().into(),
+            additional_roots: Vec::new(),
             digest_file: None,
+            compute_digests: false,
+            trust_cache_when_unchanged: false,
         }
     }
 
+    /// Adds another, possibly unrelated, directory to the table. Its entries
+    /// are stored under `label`, so that duplicates can be found across roots
+    /// while the paths stay distinguishable from those in the main root.
+    ///
+    /// The label must be a single path component, which is not already used
+    /// by an entry in the main root or by another added root.
+    pub fn add_root<P: AsRef<Path>>(&mut self, label: &str, root: P) -> io::Result<&mut Self> {
+        let invalid = |message: &str| {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("root label {:?} {}", label, message),
+            ))
+        };
+        let mut components = Path::new(label).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None) if name == label => {}
+            _ => return invalid("must be a single path component"),
+        }
+        if self
+            .additional_roots
+            .iter()
+            .any(|(other, _)| other == label)
+        {
+            return invalid("is already used by another root");
+        }
+        if self.root.join(label).symlink_metadata().is_ok() {
+            return invalid("is already used by an entry in the main root");
+        }
+
+        self.additional_roots
+            .push((label.to_string(), root.as_ref().into()));
+        Ok(self)
+    }
+
+    /// Hash the files while ingesting them. Off by default, since statistics
+    /// like [`Dedup::inventory`] don't need the digests.
+    pub fn compute_digests(&mut self, compute_digests: bool) -> &mut Self {
+        self.compute_digests = compute_digests;
+        self
+    }
+
+    /// Check a loaded digest file against the tree before using it: the file
+    /// count, and the size and modification time of a sample of the entries.
+    /// The tree is ingested again if any of them changed. Off by default, in
+    /// which case any digest file which can be read is used as-is.
+    pub fn trust_cache_when_unchanged(&mut self, trust_cache_when_unchanged: bool) -> &mut Self {
+        self.trust_cache_when_unchanged = trust_cache_when_unchanged;
+        self
+    }
+
+    // True if the tree looks the same as when the digest file was saved.
+    // Files modified after the digest file was written count as changed.
+    fn cache_matches_tree(&self, digest_file: &Path, table: &FsTable) -> bool {
+        const SAMPLE_SIZE: usize = 64;
+        let saved = match std::fs::metadata(digest_file).and_then(|m| m.modified()) {
+            Ok(saved) => saved,
+            Err(_) => return false,
+        };
+
+        let file_count = jwalk::WalkDir::new(&self.root)
+            .follow_links(false)
+            .into_iter()
+            .filter(|entry| matches!(entry, Ok(entry) if entry.file_type().is_file()))
+            .count();
+        let entries: Vec<&TableEntry> = table.iter_files().collect();
+        if entries.len() != file_count {
+            return false;
+        }
+
+        let step = (entries.len() / SAMPLE_SIZE).max(1);
+        entries.iter().step_by(step).all(|entry| {
+            match std::fs::symlink_metadata(self.root.join(entry.path())) {
+                Ok(metadata) => {
+                    metadata.len() == entry.size()
+                        && matches!(metadata.modified(), Ok(modified) if modified <= saved)
+                }
+                Err(_) => false,
+            }
+        })
+    }
+
     pub fn with_digest_file<P: AsRef<Path>>(&mut self, digest_file: P) -> &mut Self {
         self.digest_file = Some(digest_file.as_ref().into());
         self
     }
 
     pub fn build(&self) -> Dedup {
+        // The digest file doesn't record which roots were added, so a table
+        // with additional roots is neither loaded from it nor saved to it
+        let digest_file = self.digest_file.as_ref().filter(|path| {
+            let use_cache = self.additional_roots.is_empty();
+            if !use_cache {
+                log::warn!("phase=load path={:?} skipped=\"additional roots\"", path);
+            }
+            use_cache
+        });
+
         // Get file system table - either from a provided table file,
         // or by scanning the root path
-        let stored_table = self.digest_file.as_ref().and_then(|path| {
-            match load_entries_from_file(&path) {
-                Ok(entries) => Some(entries),
+        let stored_table = digest_file.and_then(|path| {
+            let start = Instant::now();
+            match persistence::load(&path) {
+                Ok(entries)
+                    if self.trust_cache_when_unchanged
+                        && !self.cache_matches_tree(path, &entries) =>
+                {
+                    log::warn!("phase=load path={:?} skipped=\"tree changed\"", path);
+                    None
+                }
+                Ok(entries) => {
+                    log::info!(
+                        "phase=load path={:?} entries={} elapsed={:?}",
+                        path,
+                        entries.len(),
+                        start.elapsed()
+                    );
+                    Some(entries)
+                }
                 Err(err) => {
-                    // log errroer
+                    log::warn!("phase=load path={:?} error=\"{}\"", path, err);
                     // delete file - we can't read it so it may be corrupted
                     None
                 }
@@ -44,10 +169,36 @@ impl DedupBuilder {
         let table = match stored_table {
             Some(table) => table,
             None => {
-                let entries = FsIngester::new(&self.root).ingest();
-                match self.digest_file.as_ref() {
+                let start = Instant::now();
+                let ingest = |root: &Path| {
+                    FsIngester::new(root)
+                        .compute_digests(self.compute_digests)
+                        .ingest()
+                };
+                let mut entries = ingest(&self.root);
+                for (label, root) in &self.additional_roots {
+                    entries.extend_at(&ingest(root), label);
+                }
+                log::info!(
+                    "phase=ingest root={:?} entries={} elapsed={:?}",
+                    self.root,
+                    entries.len(),
+                    start.elapsed()
+                );
+
+                match digest_file {
                     Some(path) => {
-                        let _res = save_entries_to_file(path, &entries);
+                        let start = Instant::now();
+                        match persistence::save(path, &entries) {
+                            Ok(()) => log::info!(
+                                "phase=save path={:?} elapsed={:?}",
+                                path,
+                                start.elapsed()
+                            ),
+                            Err(err) => {
+                                log::warn!("phase=save path={:?} error=\"{}\"", path, err)
+                            }
+                        }
                     }
                     None => {}
                 }
@@ -57,14 +208,77 @@ impl DedupBuilder {
 
         Dedup {
             root: self.root.clone(),
+            additional_roots: self.additional_roots.clone(),
             digest_file: self.digest_file.clone(),
             table,
         }
     }
 }
 
+/// File count and size statistics, computed without hashing any files.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Inventory {
+    pub file_count: usize,
+    pub total_bytes: u64,
+    /// (size, file count) for each non-zero size shared by more than one
+    /// file, largest size first. Only these files can have duplicates.
+    pub candidate_sizes: Vec<(u64, usize)>,
+    /// (extension, file count, total bytes) for each file extension, largest
+    /// total first. Extensions are lowercase, files without one are counted
+    /// under "".
+    pub extensions: Vec<(String, usize, u64)>,
+}
+
+impl Inventory {
+    /// Computes the inventory of a table, for example one loaded from a
+    /// digest file.
+    pub fn from_table(table: &FsTable) -> Inventory {
+        let mut inventory = Inventory::default();
+        let mut size_counts = HashMap::<u64, usize>::new();
+        let mut extension_totals = HashMap::<String, (usize, u64)>::new();
+        for entry in table.iter_files() {
+            inventory.file_count += 1;
+            inventory.total_bytes += entry.size();
+            *size_counts.entry(entry.size()).or_insert(0) += 1;
+
+            let extension = entry
+                .path()
+                .extension()
+                .map_or(String::new(), |ext| ext.to_string_lossy().to_lowercase());
+            let totals = extension_totals.entry(extension).or_insert((0, 0));
+            totals.0 += 1;
+            totals.1 += entry.size();
+        }
+
+        let mut candidate_sizes: Vec<_> = size_counts
+            .into_iter()
+            .filter(|&(size, count)| size > 0 && count > 1)
+            .collect();
+        candidate_sizes.sort_unstable_by_key(|&(size, _)| std::cmp::Reverse(size));
+        inventory.candidate_sizes = candidate_sizes;
+
+        let mut extensions: Vec<_> = extension_totals
+            .into_iter()
+            .map(|(extension, (count, bytes))| (extension, count, bytes))
+            .collect();
+        extensions.sort_unstable_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+        inventory.extensions = extensions;
+        inventory
+    }
+
+    /// Upper bound for the bytes dedup could reclaim: assumes every
+    /// same-size file is a duplicate.
+    pub fn max_reclaimable_bytes(&self) -> u64 {
+        self.candidate_sizes
+            .iter()
+            .map(|&(size, count)| size * (count as u64 - 1))
+            .sum()
+    }
+}
+
 pub struct Dedup {
     root: PathBuf,
+    additional_roots: Vec<(String, PathBuf)>,
     digest_file: Option<PathBuf>,
     table: FsTable
 }
@@ -81,10 +295,139 @@ impl Dedup {
 
     pub fn dedup(&mut self) {}
 
+    /// Finds the files in `dir_b` which already exist in `dir_a`: each file
+    /// in `dir_b` with the same content as a file in `dir_a` is returned
+    /// together with that file, the first in path order if there are several.
+    /// Duplicates within one of the directories are not paired, and empty
+    /// files are never matched.
+    pub fn cross_duplicates<A: AsRef<Path>, B: AsRef<Path>>(
+        dir_a: A,
+        dir_b: B,
+    ) -> Vec<(TableEntry, TableEntry)> {
+        let scan = |dir: &Path| FsIngester::new(dir).compute_digests(true).ingest();
+        let table_a = scan(dir_a.as_ref());
+        let table_b = scan(dir_b.as_ref());
+
+        let mut files_a = HashMap::new();
+        for entry in table_a.iter_files().filter(|entry| entry.size() > 0) {
+            if let Some(digest) = entry.digest() {
+                files_a
+                    .entry((entry.size(), digest))
+                    .and_modify(|first: &mut &TableEntry| {
+                        if entry.path() < first.path() {
+                            *first = entry;
+                        }
+                    })
+                    .or_insert(entry);
+            }
+        }
+        table_b
+            .iter_files()
+            .filter_map(|entry| {
+                let first = files_a.get(&(entry.size(), entry.digest()?))?;
+                Some(((*first).clone(), entry.clone()))
+            })
+            .collect()
+    }
+
+    /// Links the files in `external_dir` which already exist in the table
+    /// to the table's copy, and returns the number of bytes reclaimed.
+    /// The directory is walked rather than ingested, and only files with the
+    /// size of a table entry are hashed. Files which can't be read or
+    /// linked are logged and left alone.
+    pub fn dedup_against<P: AsRef<Path>>(&self, external_dir: P) -> u64 {
+        let start = Instant::now();
+
+        // Table digests are filled in when first needed, if the table was
+        // ingested without them
+        let mut by_size = HashMap::<u64, Vec<(&TableEntry, Option<u128>)>>::new();
+        for entry in self.table.iter_files().filter(|entry| entry.size() > 0) {
+            by_size
+                .entry(entry.size())
+                .or_default()
+                .push((entry, entry.digest().map(NonZeroU128::get)));
+        }
+
+        let mut linked = 0;
+        let mut reclaimed_bytes = 0;
+        for dir_entry in jwalk::WalkDir::new(external_dir.as_ref()).follow_links(false) {
+            let dir_entry = match dir_entry {
+                Ok(dir_entry) => dir_entry,
+                Err(err) => {
+                    log::warn!("phase=dedup_against error=\"{}\"", err);
+                    continue;
+                }
+            };
+            if !dir_entry.file_type().is_file() {
+                continue;
+            }
+            let path = dir_entry.path();
+            let candidates = match dir_entry
+                .metadata()
+                .ok()
+                .and_then(|metadata| by_size.get_mut(&metadata.len()))
+            {
+                Some(candidates) => candidates,
+                None => continue,
+            };
+            let digest = match file_digest(&path) {
+                Ok(digest) => digest,
+                Err(err) => {
+                    log::warn!("phase=dedup_against path={:?} error=\"{}\"", path, err);
+                    continue;
+                }
+            };
+
+            for (entry, entry_digest) in candidates.iter_mut() {
+                let library_path = self.disk_path(entry);
+                if entry_digest.is_none() {
+                    *entry_digest = file_digest(&library_path).ok();
+                }
+                if *entry_digest != Some(digest) {
+                    continue;
+                }
+                match link_duplicate(&library_path, &path) {
+                    Ok(true) => {
+                        linked += 1;
+                        reclaimed_bytes += entry.size();
+                    }
+                    Ok(false) => {}
+                    Err(err) => {
+                        log::warn!("phase=dedup_against path={:?} error=\"{}\"", path, err)
+                    }
+                }
+                break;
+            }
+        }
+        log::info!(
+            "phase=dedup_against path={:?} linked={} bytes={} elapsed={:?}",
+            external_dir.as_ref(),
+            linked,
+            reclaimed_bytes,
+            start.elapsed()
+        );
+        reclaimed_bytes
+    }
+
+    // The location of a table entry on disk. Entries from additional roots
+    // are stored under the root's label.
+    fn disk_path(&self, entry: &TableEntry) -> PathBuf {
+        for (label, root) in &self.additional_roots {
+            if let Ok(relative) = entry.path().strip_prefix(label) {
+                return root.join(relative);
+            }
+        }
+        self.root.join(entry.path())
+    }
+
     pub fn dedup_additional<P: AsRef<Path>>(&mut self, dir: P) {
         let _entries = Dedup::scan(dir);
     }
 
+    pub fn inventory(&self) -> Inventory {
+        Inventory::from_table(&self.table)
+    }
+
     pub fn stats(&self) {
         let mut size = 0u64;
         for entry in self.table.iter_files() {
@@ -92,37 +435,426 @@ impl Dedup {
         }
 
         println!("file count {}", self.table.len());
-        println!("totl size  {}", size);
+        println!("total size {}", format_bytes(size));
     }
 
     pub fn stats_marginal<P: AsRef<Path>>(&self, dir: P) {}
 }
 
-#[derive(Error, Debug)]
-enum EntriesFileError {
-    #[error("file io error")]
-    FileIo(#[from] io::Error),
-    #[error("data format error")]
-    DataFormat(#[from] Box<bincode::ErrorKind>),
+// Blake3 digest truncated to 128 bits, the same as the digests in the table
+fn file_digest(path: &Path) -> io::Result<u128> {
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    let mut buffer = [0u8; 16];
+    hasher.finalize_xof().fill(&mut buffer);
+    Ok(u128::from_le_bytes(buffer))
 }
 
-fn load_entries_from_file(path: &Path) -> Result<FsTable, EntriesFileError> {
-    let compressed_bytes = std::fs::read(path)?;
-    let bytes = zstd::stream::decode_all(&*compressed_bytes)?;
-    let entries = bincode::deserialize(&bytes)?;
-    Ok(entries)
+// Replaces `duplicate` with a hardlink to `keeper`, through a temporary link
+// which is renamed over it, so the duplicate path never goes missing.
+// Returns false if the duplicate already was a link to the keeper.
+fn link_duplicate(keeper: &Path, duplicate: &Path) -> io::Result<bool> {
+    if same_file(keeper, duplicate)? {
+        return Ok(false);
+    }
+    let mut temp_name = duplicate.file_name().unwrap().to_os_string();
+    temp_name.push(".dedup-tmp");
+    let temp_path = duplicate.with_file_name(temp_name);
+
+    std::fs::hard_link(keeper, &temp_path)?;
+    if let Err(err) = std::fs::rename(&temp_path, duplicate) {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(err);
+    }
+    Ok(true)
 }
 
-fn save_entries_to_file(path: &Path, entries: &FsTable) -> Result<(), EntriesFileError> {
-    let bytes = bincode::serialize(entries)?;
-    let compressed_bytes = zstd::stream::encode_all(&*bytes, 0)?;
-    std::fs::write(path, &compressed_bytes)?;
-    Ok(())
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let (a, b) = (std::fs::metadata(a)?, std::fs::metadata(b)?);
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &Path, _b: &Path) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Formats a byte count for display, using binary units with one decimal
+/// for anything from 1 KiB and up.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Formats a 128-bit digest as 32 lowercase hex digits, most significant
+/// first. This formats the numeric value, not the in-memory bytes, so the
+/// output does not depend on host endianness.
+pub fn digest_hex(digest: u128) -> String {
+    format!("{:032x}", digest)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+    use std::sync::Mutex;
+
+    // A temporary directory, unique to the test, which is removed when the
+    // test is done
+    pub(crate) struct Fixture {
+        dir: PathBuf,
+    }
+
+    impl Fixture {
+        pub(crate) fn new(name: &str) -> Fixture {
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!(
+                "dedup_{}_{}_{}",
+                name,
+                std::process::id(),
+                COUNT.fetch_add(1, AtomicOrdering::Relaxed)
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Fixture { dir }
+        }
+
+        pub(crate) fn root(&self) -> &Path {
+            &self.dir
+        }
+
+        pub(crate) fn path<P: AsRef<Path>>(&self, relative: P) -> PathBuf {
+            self.dir.join(relative)
+        }
+
+        // Writes a file, creating its parent directories
+        pub(crate) fn write<P: AsRef<Path>, C: AsRef<[u8]>>(
+            &self,
+            relative: P,
+            contents: C,
+        ) -> PathBuf {
+            let path = self.path(relative);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, contents).unwrap();
+            path
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    static LOG_RECORDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            LOG_RECORDS
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", record.level(), record.args()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn format_bytes_boundaries() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1024), "1.0 KiB");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(1024 * 1024), "1.0 MiB");
+        assert_eq!(format_bytes(10485760), "10.0 MiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+        assert_eq!(format_bytes(u64::MAX), "16.0 EiB");
+    }
+
+    #[test]
+    fn digest_hex_is_big_endian() {
+        assert_eq!(digest_hex(0), "00000000000000000000000000000000");
+        assert_eq!(digest_hex(1), "00000000000000000000000000000001");
+        assert_eq!(
+            digest_hex(0x0123456789abcdef_fedcba9876543210),
+            "0123456789abcdeffedcba9876543210"
+        );
+    }
+
+    #[test]
+    fn inventory_reports_size_candidates() {
+        let fixture = Fixture::new("inventory");
+        fixture.write("a.txt", [1u8; 100]);
+        fixture.write("sub/b.TXT", [2u8; 100]);
+        fixture.write("c.rs", [3u8; 50]);
+        fixture.write("empty1", []);
+        fixture.write("empty2", []);
+
+        let dedup = DedupBuilder::new(fixture.root()).build();
+        assert!(dedup
+            .table
+            .iter_files()
+            .all(|entry| entry.digest().is_none()));
+        let inventory = dedup.inventory();
+        assert_eq!(inventory.file_count, 5);
+        assert_eq!(inventory.total_bytes, 250);
+        assert_eq!(inventory.candidate_sizes, vec![(100, 2)]);
+        assert_eq!(inventory.max_reclaimable_bytes(), 100);
+        assert_eq!(
+            inventory.extensions,
+            vec![
+                ("txt".to_string(), 2, 200),
+                ("rs".to_string(), 1, 50),
+                (String::new(), 2, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn multiple_roots() {
+        let fixture = Fixture::new("roots");
+        fixture.write("a/shared", [7u8; 300]);
+        fixture.write("a/only_a", [1u8; 10]);
+        fixture.write("b/shared_copy", [7u8; 300]);
+        fixture.write("b/only_b", [2u8; 20]);
+
+        let dedup = DedupBuilder::new(fixture.path("a"))
+            .compute_digests(true)
+            .add_root("backup", fixture.path("b"))
+            .unwrap()
+            .build();
+        let inventory = dedup.inventory();
+        assert_eq!(inventory.file_count, 4);
+        assert_eq!(inventory.candidate_sizes, vec![(300, 2)]);
+
+        let mut groups = HashMap::<_, Vec<&Path>>::new();
+        for entry in dedup.table.iter_files() {
+            let digest = entry.digest().unwrap();
+            groups.entry(digest).or_default().push(entry.path());
+        }
+        let duplicates: Vec<_> = groups.values().filter(|paths| paths.len() > 1).collect();
+        assert_eq!(duplicates.len(), 1);
+        let mut paths = duplicates[0].clone();
+        paths.sort();
+        assert_eq!(paths.len(), 2);
+        assert!(paths[0].starts_with("backup"));
+        assert!(paths[0].ends_with("shared_copy"));
+        assert!(!paths[1].starts_with("backup"));
+        assert!(paths[1].ends_with("shared"));
+    }
+
+    #[test]
+    fn root_labels_are_validated() {
+        let fixture = Fixture::new("root_labels");
+        fixture.write("main/taken", [1u8; 10]);
+        fixture.write("other/file", [2u8; 10]);
+        let mut builder = DedupBuilder::new(fixture.path("main"));
+        for label in &["", ".", "..", "a/b", "/abs", "taken"] {
+            let err = builder.add_root(label, fixture.path("other")).err();
+            assert_eq!(err.map(|err| err.kind()), Some(io::ErrorKind::InvalidInput));
+        }
+        assert!(builder.add_root("backup", fixture.path("other")).is_ok());
+        assert!(builder.add_root("backup", fixture.path("other")).is_err());
+    }
+
+    #[test]
+    fn additional_roots_skip_the_digest_file() {
+        let fixture = Fixture::new("roots_cache");
+        fixture.write("a/file", [1u8; 10]);
+        fixture.write("b/file", [2u8; 10]);
+        let digest_file = fixture.path("digest");
+
+        let single = DedupBuilder::new(fixture.path("a"))
+            .with_digest_file(&digest_file)
+            .build();
+        assert_eq!(single.inventory().file_count, 1);
+        let saved = std::fs::read(&digest_file).unwrap();
+
+        let multiple = DedupBuilder::new(fixture.path("a"))
+            .with_digest_file(&digest_file)
+            .add_root("backup", fixture.path("b"))
+            .unwrap()
+            .build();
+        assert_eq!(multiple.inventory().file_count, 2);
+        assert_eq!(std::fs::read(&digest_file).unwrap(), saved);
+    }
+
+    #[test]
+    fn cross_duplicates() {
+        let fixture = Fixture::new("cross");
+        fixture.write("a/x", [1u8; 100]);
+        fixture.write("a/x_copy", [1u8; 100]); // duplicate within a
+        fixture.write("a/only_a", [2u8; 100]);
+        fixture.write("a/empty", []);
+        fixture.write("b/y", [1u8; 100]);
+        fixture.write("b/z", [3u8; 100]);
+        fixture.write("b/z_copy", [3u8; 100]); // duplicate within b
+        fixture.write("b/empty", []);
+
+        let pairs = Dedup::cross_duplicates(fixture.path("a"), fixture.path("b"));
+        assert_eq!(pairs.len(), 1);
+        let (a, b) = &pairs[0];
+        assert!(a.path().ends_with("x"));
+        assert!(b.path().ends_with("y"));
+        assert_eq!(a.digest(), b.digest());
+        assert!(a.digest().is_some());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dedup_against_links_to_the_library() {
+        use std::os::unix::fs::MetadataExt;
+        let ino = |path: &Path| std::fs::metadata(path).unwrap().ino();
+
+        // Table digests are used when ingested, and computed otherwise
+        for &compute_digests in &[false, true] {
+            let fixture = Fixture::new("dedup_against");
+            let book = fixture.write("library/book.pdf", [1u8; 200]);
+            let photo = fixture.write("library/photo.jpg", [2u8; 200]);
+            let old = fixture.write("backup/old.txt", [3u8; 200]);
+            let book_copy = fixture.write("downloads/book (1).pdf", [1u8; 200]);
+            let photo_copy = fixture.write("downloads/nested/photo.jpg", [2u8; 200]);
+            let old_copy = fixture.write("downloads/old.txt", [3u8; 200]);
+            let new = fixture.write("downloads/new.bin", [4u8; 200]);
+
+            let dedup = DedupBuilder::new(fixture.path("library"))
+                .add_root("backup", fixture.path("backup"))
+                .unwrap()
+                .compute_digests(compute_digests)
+                .build();
+            assert_eq!(dedup.dedup_against(fixture.path("downloads")), 600);
+
+            assert_eq!(ino(&book_copy), ino(&book));
+            assert_eq!(ino(&photo_copy), ino(&photo));
+            assert_eq!(ino(&old_copy), ino(&old));
+            assert_eq!(std::fs::read(&new).unwrap(), [4u8; 200]);
+            assert_eq!(std::fs::metadata(&new).unwrap().nlink(), 1);
+            assert!(!fixture.path("downloads/book (1).pdf.dedup-tmp").exists());
+
+            // Already linked files are not counted again
+            assert_eq!(dedup.dedup_against(fixture.path("downloads")), 0);
+        }
+    }
+
+    #[test]
+    fn build_logs_phases() {
+        let _ = log::set_logger(&CaptureLogger);
+        log::set_max_level(log::LevelFilter::Info);
+
+        let fixture = Fixture::new("logging");
+        fixture.write("tree/file", [0u8; 10]);
+        let dir = &fixture.path("tree");
+        let digest_file = &fixture.path("digest");
+
+        DedupBuilder::new(dir).with_digest_file(digest_file).build();
+        DedupBuilder::new(dir).with_digest_file(digest_file).build();
+
+        let records = LOG_RECORDS.lock().unwrap();
+        // Other tests log concurrently, so only look at records for this
+        // test's paths
+        let find = |prefix: &str, path: &Path| -> Vec<&String> {
+            let path = format!("{:?}", path);
+            records
+                .iter()
+                .filter(|record| record.starts_with(prefix) && record.contains(&path))
+                .collect()
+        };
+        assert_eq!(find("WARN phase=load", digest_file).len(), 1); // no digest file yet
+        assert_eq!(find("INFO phase=save", digest_file).len(), 1);
+        let ingest = find("INFO phase=ingest", dir);
+        assert_eq!(ingest.len(), 1);
+        assert!(ingest[0].contains("entries=1 "));
+        let load = find("INFO phase=load", digest_file);
+        assert_eq!(load.len(), 1);
+        assert!(load[0].contains("entries=1 "));
+    }
+
+    #[test]
+    fn trusted_cache_is_checked_against_the_tree() {
+        let _ = log::set_logger(&CaptureLogger);
+        log::set_max_level(log::LevelFilter::Info);
+
+        let fixture = Fixture::new("trust_cache");
+        fixture.write("tree/a", [0u8; 10]);
+        fixture.write("tree/b", [1u8; 20]);
+        let dir = &fixture.path("tree");
+        let digest_file = &fixture.path("digest");
+        let build = || {
+            DedupBuilder::new(dir)
+                .with_digest_file(digest_file)
+                .compute_digests(true)
+                .trust_cache_when_unchanged(true)
+                .build()
+                .inventory()
+        };
+        let ingest_count = || {
+            let path = format!("{:?}", dir);
+            LOG_RECORDS
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|record| record.starts_with("INFO phase=ingest") && record.contains(&path))
+                .count()
+        };
+
+        assert_eq!(build().total_bytes, 30);
+        assert_eq!(ingest_count(), 1);
+
+        // Unchanged: the digest file is used, and nothing is hashed again
+        assert_eq!(build().total_bytes, 30);
+        assert_eq!(ingest_count(), 1);
+
+        // A changed file is ingested again
+        fixture.write("tree/b", [1u8; 25]);
+        assert_eq!(build().total_bytes, 35);
+        assert_eq!(ingest_count(), 2);
+
+        // So is an added one
+        fixture.write("tree/c", [2u8; 5]);
+        assert_eq!(build().total_bytes, 40);
+        assert_eq!(ingest_count(), 3);
+
+        assert_eq!(build().total_bytes, 40);
+        assert_eq!(ingest_count(), 3);
+    }
+
+    #[test]
+    fn prelude_exports() {
+        use crate::prelude::*;
+
+        let fixture = Fixture::new("prelude");
+        fixture.write("file", [0u8; 10]);
+        let dir = fixture.root();
+
+        let table: FsTable = FsIngester::new(dir).ingest();
+        let entries: Vec<&TableEntry> = table.iter_files().collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].size(), 10);
+        let dedup: Dedup = DedupBuilder::new(dir).build();
+        let inventory: Inventory = dedup.inventory();
+        assert_eq!(inventory.file_count, table.len());
+
+        let missing = dir.join("missing_digest_file");
+        let result: Result<FsTable, EntriesFileError> = persistence::load(&missing);
+        assert!(result.is_err());
+    }
 
     #[test]
     fn it_works() {
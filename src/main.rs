@@ -1,23 +1,139 @@
-use clap::{App, Arg};
-use indicatif::{MultiProgress, ProgressBar, ProgressIterator, ProgressStyle};
+use clap::{App, Arg, ArgMatches};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use jwalk::{DirEntry, WalkDir};
-use rayon::iter::{ParallelBridge, ParallelIterator};
+use rayon::iter::ParallelIterator;
 use rayon::prelude::*;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use std::{cmp::Ordering, sync::Arc};
-use std::{io::Read, path::Path, sync::Mutex};
+use std::cmp::Ordering;
+use std::io::{self, BufRead, Write};
+use std::{io::Read, path::Path};
 // use std::{cmp::Ordering, collections::HashMap, fs::File,
 extern crate num_cpus;
 use crossbeam::atomic::AtomicCell;
 use itertools::Itertools;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::fmt;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::time::{Duration, Instant};
+use structureddedup::persistence::{self, EntriesFileError, Header};
+use structureddedup::{digest_hex, format_bytes, Inventory};
 
 type JWalkDirEntry = DirEntry<((), ())>;
 
+// Moves the error for a directory which could not be read out of its entry.
+// The entry itself is kept, only its contents are missing.
+fn take_walk_error(
+    result: jwalk::Result<JWalkDirEntry>,
+    errors: &mut Vec<jwalk::Error>,
+) -> Option<JWalkDirEntry> {
+    match result {
+        Ok(mut entry) => {
+            if let Some(err) = entry.read_children_error.take() {
+                errors.push(err);
+            }
+            Some(entry)
+        }
+        Err(err) => {
+            errors.push(err);
+            None
+        }
+    }
+}
+
 fn scan_dir2(path: &str) -> Vec<JWalkDirEntry> {
+    let start = Instant::now();
+    let (entries, errors) = scan_dir_with_errors(path);
+    for err in &errors {
+        eprintln!("Skipped: {}", err);
+    }
+    log::info!(
+        "phase=walk root={:?} entries={} elapsed={:?}",
+        path,
+        entries.len(),
+        start.elapsed()
+    );
+    entries
+}
+
+// Total number of entries found by the scan workers, shown in a progress bar
+// below the per-worker spinners
+struct ScanProgress {
+    bar: ProgressBar,
+    discovered: AtomicUsize,
+    running_workers: AtomicUsize,
+}
+
+impl ScanProgress {
+    // Redraw interval for the total, in entries. A power of two, so the
+    // check is a cheap mask.
+    const UPDATE_INTERVAL: usize = 1024;
+
+    fn new(bar: ProgressBar, workers: usize) -> ScanProgress {
+        bar.set_message("Scanning");
+        let progress = ScanProgress {
+            bar,
+            discovered: AtomicUsize::new(0),
+            running_workers: AtomicUsize::new(workers),
+        };
+        if workers == 0 {
+            progress.finish();
+        }
+        progress
+    }
+
+    fn add_entry(&self) {
+        let discovered = self.discovered.fetch_add(1, AtomicOrdering::Relaxed) + 1;
+        if discovered & (Self::UPDATE_INTERVAL - 1) == 0 {
+            self.bar
+                .set_message(&format!("Scanning, found {} entries", discovered));
+        }
+    }
+
+    fn discovered(&self) -> usize {
+        self.discovered.load(AtomicOrdering::Relaxed)
+    }
+
+    // The total is final when the last worker is done
+    fn worker_done(&self) {
+        if self.running_workers.fetch_sub(1, AtomicOrdering::AcqRel) == 1 {
+            self.finish();
+        }
+    }
+
+    fn finish(&self) {
+        self.bar
+            .finish_with_message(&format!("Found {} entries", self.discovered()));
+    }
+}
+
+// Walks one subdirectory of the scan. Unreadable directories are recorded,
+// and the walk continues with their siblings.
+fn scan_subdir(
+    path: &str,
+    pb: &ProgressBar,
+    progress: &ScanProgress,
+    errors: &mut Vec<jwalk::Error>,
+) -> Vec<JWalkDirEntry> {
+    WalkDir::new(path)
+        .follow_links(false)
+        .parallelism(jwalk::Parallelism::Serial) // TODO: use threadpool
+        .sort(true)
+        .into_iter()
+        .filter_map(|result| take_walk_error(result, errors))
+        .inspect(|_| {
+            pb.tick();
+            progress.add_entry();
+        })
+        .collect()
+}
+
+// Scans the path, and returns the entries together with errors for the
+// parts of the tree which could not be read.
+fn scan_dir_with_errors(path: &str) -> (Vec<JWalkDirEntry>, Vec<jwalk::Error>) {
     let threads = num_cpus::get();
+    let mut errors = Vec::new();
 
     // find all immediate subdirectories of the given path
     let roots: Vec<_> = WalkDir::new(path)
@@ -25,59 +141,64 @@ fn scan_dir2(path: &str) -> Vec<JWalkDirEntry> {
         .sort(true)
         .max_depth(1)
         .into_iter()
-        .filter_map(Result::ok)
+        .filter_map(|result| take_walk_error(result, &mut errors))
         .filter(|item| item.file_type().is_dir())
         .skip(1) // skip "path"
         .collect();
 
-    // scan each subdirectoy, print progress to stdout, collect entries
-    let entries = Arc::new(Mutex::new(Vec::<DirEntry<((), ())>>::new()));
+    // scan each subdirectoy, print progress to stdout. Each worker collects
+    // into its own slot, so the workers don't contend on a shared lock.
+    let mut results: Vec<(Vec<JWalkDirEntry>, Vec<jwalk::Error>)> =
+        roots.iter().map(|_| (Vec::new(), Vec::new())).collect();
     let pool = rayon::ThreadPoolBuilder::new()
         .num_threads(threads + 1)
         .build()
         .unwrap();
+    let m = MultiProgress::new();
+    let progress = ScanProgress::new(m.add(ProgressBar::new_spinner()), roots.len());
+    let progress = &progress;
     pool.install(|| {
         rayon::scope(|s| {
-            let m = MultiProgress::new();
-            for i in 0..roots.len() {
+            for (root, result) in roots.iter().zip(results.iter_mut()) {
                 let pb = m.add(ProgressBar::new(0));
-                let root = &roots[i];
-                let entries2 = entries.clone();
                 s.spawn(move |_| {
                     let path = root.path().to_string_lossy().to_string();
 
                     pb.set_style(ProgressStyle::default_spinner().clone());
                     pb.set_message(&format!("Scanning {}", path));
 
-                    let e: Vec<_> = WalkDir::new(path)
-                        .follow_links(false)
-                        .parallelism(jwalk::Parallelism::Serial) // TODO: use threadpool
-                        .sort(true)
-                        .into_iter()
-                        .filter_map(Result::ok)
-                        .inspect(|_| {
-                            pb.tick();
-                        })
-                        .collect();
+                    let mut errs = Vec::new();
+                    let e = scan_subdir(&path, &pb, progress, &mut errs);
                     let length = e.len();
-
-                    entries2.lock().unwrap().extend(e);
+                    *result = (e, errs);
 
                     pb.finish_with_message(&format!("Done ({}) {:?}", length, root.path()));
+                    progress.worker_done();
                 });
             }
             m.join().unwrap();
         });
     });
 
-    // unwrap the entries
-    let mut final_entries = Arc::try_unwrap(entries).unwrap().into_inner().unwrap();
+    // merge the worker results
+    let entry_count = results.iter().map(|(e, _)| e.len()).sum();
+    let mut final_entries = Vec::with_capacity(entry_count);
+    for (e, errs) in results {
+        final_entries.extend(e);
+        errors.extend(errs);
+    }
 
-    println!("Sorting by name");
+    eprintln!("Sorting by name");
 
-    final_entries.par_sort_unstable_by(|a, b| a.file_name().cmp(b.file_name()));
+    // Ties are broken by the parent directory, so that the order does not
+    // depend on which worker finished first
+    final_entries.par_sort_unstable_by(|a, b| {
+        a.file_name()
+            .cmp(b.file_name())
+            .then_with(|| a.parent_path.cmp(&b.parent_path))
+    });
 
-    return final_entries;
+    (final_entries, errors)
 }
 struct AtomicCellU128(AtomicCell<Option<u128>>);
 
@@ -95,6 +216,12 @@ impl AtomicCellU128 {
     }
 }
 
+impl Clone for AtomicCellU128 {
+    fn clone(&self) -> AtomicCellU128 {
+        AtomicCellU128::new(self.load())
+    }
+}
+
 impl PartialEq for AtomicCellU128 {
     fn eq(&self, other: &Self) -> bool {
         self.0.load() == other.0.load()
@@ -143,21 +270,68 @@ impl<'de> Deserialize<'de> for AtomicCellU128 {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq)]
+#[cfg(unix)]
+fn inode_of(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn inode_of(_metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+// Permission bits, including setuid, setgid and sticky
+#[cfg(unix)]
+fn mode_of(metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn mode_of(_metadata: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+// Fifos, sockets and devices. Reading them may block or never end, and
+// they can't be hardlinked as duplicates.
+#[cfg(unix)]
+fn is_special(file_type: std::fs::FileType) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    file_type.is_fifo()
+        || file_type.is_socket()
+        || file_type.is_block_device()
+        || file_type.is_char_device()
+}
+
+#[cfg(not(unix))]
+fn is_special(_file_type: std::fs::FileType) -> bool {
+    false
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq)]
 struct FileEntry {
     name: String,
     path: PathBuf,
     len: u64,
     digest: AtomicCellU128,
+    // (device, inode), where the platform has them
+    #[serde(default)]
+    inode: Option<(u64, u64)>,
+    // Unix permission bits, where the platform has them
+    #[serde(default)]
+    mode: Option<u32>,
 }
 
 impl FileEntry {
     fn from_jwalk_entry(dir_entry: &JWalkDirEntry) -> Option<FileEntry> {
-        // Skip files with non-unicode names
-        let file_name = match dir_entry.file_name().to_str() {
-            Some(name) => name,
-            None => return None,
-        };
+        // The name is for display only; the path keeps the exact bytes
+        let file_name = dir_entry.file_name().to_string_lossy();
+
+        // Skip directories and special files, only files can be duplicates
+        if dir_entry.file_type().is_dir() || is_special(dir_entry.file_type()) {
+            return None;
+        }
 
         // Skip files with inaccessible metadata
         let metadata = match dir_entry.metadata() {
@@ -170,6 +344,27 @@ impl FileEntry {
             path: dir_entry.path(),
             len: metadata.len(),
             digest: AtomicCellU128::new(None),
+            inode: inode_of(&metadata),
+            mode: mode_of(&metadata),
+        })
+    }
+
+    fn from_path(path: PathBuf) -> Option<FileEntry> {
+        let file_name = path.file_name()?.to_string_lossy().to_string();
+
+        // Skip directories, special files and files with inaccessible metadata
+        let metadata = std::fs::symlink_metadata(&path).ok()?;
+        if metadata.is_dir() || is_special(metadata.file_type()) {
+            return None;
+        }
+
+        Some(FileEntry {
+            name: file_name,
+            path,
+            len: metadata.len(),
+            digest: AtomicCellU128::new(None),
+            inode: inode_of(&metadata),
+            mode: mode_of(&metadata),
         })
     }
 
@@ -177,9 +372,12 @@ impl FileEntry {
         match self.digest.load() {
             Some(digest) => digest,
             None => {
-                let digest = compute_file_digest(&self.path);
-                self.digest.store(digest);
-                digest.unwrap()
+                // Scanned entries are hashed by compute_digests(), which drops
+                // the files that can't be read
+                let digest = try_file_digest(&self.path)
+                    .unwrap_or_else(|err| panic!("Error reading {:?} {:?}", self.path, err));
+                self.digest.store(Some(digest));
+                digest
             }
         }
     }
@@ -218,48 +416,119 @@ impl TryFrom<JWalkDirEntry> for FileEntry {
     }
 }
 
+const MIN_FILE_SIZE: u64 = 1024; // Skip small files
+
 fn filter_files(entries: Vec<JWalkDirEntry>) -> Vec<FileEntry> {
-    let min_file_size = 1024; // Skip small files
+    eprintln!("Verifying files/filtering small files");
     entries
         .par_iter()
         .filter_map(|jentry| {
             // match FileEntry::try_from(jentry) ### y u no work
             FileEntry::from_jwalk_entry(jentry)
         })
-        .filter(|entry| entry.len > min_file_size)
+        .filter(|entry| entry.len > MIN_FILE_SIZE)
         .collect()
 }
 
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(std::ffi::OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+// Reads a list of paths separated by `separator` (e.g. output from find), and
+// returns the entries for those of them that are files, like filter_files().
+fn read_file_list<R: BufRead>(input: R, separator: u8) -> io::Result<Vec<FileEntry>> {
+    let mut file_entries = Vec::new();
+    for bytes in input.split(separator) {
+        let bytes = bytes?;
+        if bytes.is_empty() {
+            continue;
+        }
+        if let Some(entry) = FileEntry::from_path(path_from_bytes(bytes)) {
+            if entry.len > MIN_FILE_SIZE {
+                file_entries.push(entry);
+            }
+        }
+    }
+    Ok(file_entries)
+}
+
 fn compute_digest(input: &[u8]) -> u128 {
     let mut buffer: [u8; 16] = [0; 16];
     blake3::Hasher::new()
         .update(input)
         .finalize_xof()
         .fill(&mut buffer);
-    u128::from_ne_bytes(buffer)
+    // Fixed byte order, so digests are the same on every host
+    u128::from_le_bytes(buffer)
 }
 
-fn compute_file_digest(path: &Path) -> Option<u128> {
-    match std::fs::File::open(path) {
-        Ok(mut file) => {
-            let mut data = Vec::new();
-            let _ = file.read_to_end(&mut data);
-            Some(compute_digest(&data))
-        }
-        Err(e) => {
-            panic!("Error opening {:?} {:?}", path, e);
-        }
-    }
+// Files may be gone or unreadable by the time they are hashed, so errors are
+// returned for the caller to report
+fn try_file_digest(path: &Path) -> io::Result<u128> {
+    let mut data = Vec::new();
+    std::fs::File::open(path)?.read_to_end(&mut data)?;
+    Ok(compute_digest(&data))
+}
+
+// Hashing progress is counted in bytes rather than files, so that the
+// percentage and ETA hold up when file sizes vary
+fn digest_progress_bar(entries: &[FileEntry]) -> ProgressBar {
+    let total_bytes = entries.iter().map(|entry| entry.len).sum();
+    let pb = ProgressBar::new(total_bytes);
+    pb.set_style(
+        ProgressStyle::default_bar().template("{wide_bar} {bytes}/{total_bytes} (eta {eta})"),
+    );
+    pb
 }
 
+// Hashes the files, and drops those which can't be read, so that they are
+// never grouped
 fn compute_digests(entries: &mut Vec<FileEntry>) {
-    entries.iter().progress().par_bridge().for_each(|entry| {
-        let digest = compute_file_digest(&entry.path);
-        entry.digest.store(digest);
-        //  println!("digest for {:?} {:?}", entry.path,  digest);
-    });
+    let start = Instant::now();
+    let failures = compute_digests_with_progress(entries, &digest_progress_bar(entries));
+    for (path, err) in &failures {
+        eprintln!("Skipped: {}: {}", path.display(), err);
+    }
+    entries.retain(|entry| entry.digest.load().is_some());
+    log::info!(
+        "phase=hash entries={} skipped={} elapsed={:?}",
+        entries.len(),
+        failures.len(),
+        start.elapsed()
+    );
+}
+
+// Returns the files which could not be hashed; their digests are left unset
+fn compute_digests_with_progress(
+    entries: &[FileEntry],
+    pb: &ProgressBar,
+) -> Vec<(PathBuf, io::Error)> {
+    let failures = entries
+        .par_iter()
+        .filter_map(|entry| {
+            let result = try_file_digest(&entry.path);
+            pb.inc(entry.len);
+            match result {
+                Ok(digest) => {
+                    entry.digest.store(Some(digest));
+                    None
+                }
+                Err(err) => Some((entry.path.clone(), err)),
+            }
+        })
+        .collect();
+    pb.finish();
+    failures
 }
 
+#[cfg_attr(not(test), allow(dead_code))]
 fn group_by_digest(numbers: &Vec<FileEntry>) -> impl Iterator<Item = &[FileEntry]> {
     numbers.iter().enumerate().peekable().batching(move |it| {
         match it.next() {
@@ -292,43 +561,255 @@ fn group_by_digest(numbers: &Vec<FileEntry>) -> impl Iterator<Item = &[FileEntry
     })
 }
 
-fn compute_savings(entries: Vec<JWalkDirEntry>) {
-    println!("Verifying files/filtering small files");
-    let mut file_entries = filter_files(entries);
+// Parallel version of group_by_digest(): find the group boundaries (indices
+// where the digest differs from the previous entry) in parallel, then slice.
+fn par_group_by_digest(entries: &[FileEntry]) -> Vec<&[FileEntry]> {
+    if entries.is_empty() {
+        return Vec::new();
+    }
+
+    let mut starts = vec![0];
+    starts.par_extend(
+        (1..entries.len())
+            .into_par_iter()
+            .filter(|&i| entries[i] != entries[i - 1]),
+    );
+
+    let ends = starts.iter().skip(1).copied().chain(Some(entries.len()));
+    starts
+        .iter()
+        .zip(ends)
+        .map(|(&begin, end)| &entries[begin..end])
+        .collect()
+}
+
+// Number of groups included in Savings::top_groups
+const TOP_GROUP_COUNT: usize = 10;
+
+#[derive(Serialize, Debug)]
+struct SavingsGroup {
+    digest: String,
+    size: u64,
+    paths: Vec<String>,
+    // Distinct files on disk: paths which already are hardlinks of each other
+    // count once
+    #[serde(skip)]
+    file_count: usize,
+}
+
+#[derive(Serialize, Debug)]
+struct Savings {
+    file_count: usize,
+    total_bytes: u64,
+    reclaimable_bytes: u64,
+    // Projected size after dedup, and its ratio to the current size. Both
+    // count hardlinked files once, as they are on disk.
+    deduped_bytes: u64,
+    dedup_ratio: f64,
+    group_count: usize,
+    top_groups: Vec<SavingsGroup>,
+    timings: Timings,
+}
+
+impl SavingsGroup {
+    fn from_group(group: &[FileEntry]) -> SavingsGroup {
+        SavingsGroup {
+            digest: digest_hex(group[0].load_digest()),
+            size: group[0].len,
+            paths: group
+                .iter()
+                .map(|entry| entry.path.to_string_lossy().to_string())
+                .collect(),
+            file_count: group.len() - linked_paths(group),
+        }
+    }
+
+    fn reclaimable_bytes(&self) -> u64 {
+        self.size * (self.file_count as u64 - 1)
+    }
+
+    // Bytes counted more than once because paths are already linked
+    fn linked_bytes(&self) -> u64 {
+        self.size * (self.paths.len() - self.file_count) as u64
+    }
+}
+
+// Number of paths in the group which are hardlinks of an earlier path
+fn linked_paths(group: &[FileEntry]) -> usize {
+    let mut seen = HashSet::new();
+    group
+        .iter()
+        .filter(|entry| matches!(entry.inode, Some(inode) if !seen.insert(inode)))
+        .count()
+}
+
+// Time spent in each phase of the compute pipeline
+#[derive(Serialize, Debug, Default)]
+struct Timings {
+    #[serde(serialize_with = "serialize_seconds")]
+    walk_duration: Duration,
+    #[serde(serialize_with = "serialize_seconds")]
+    hash_duration: Duration,
+    #[serde(serialize_with = "serialize_seconds")]
+    sort_duration: Duration,
+}
+
+// Durations are written to JSON as (fractional) seconds
+fn serialize_seconds<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+// Runs f, and adds the time it took to duration
+fn timed<T>(duration: &mut Duration, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    *duration += start.elapsed();
+    result
+}
+
+// Keeps one entry per (device, inode), the first path in sort order, so that
+// files which are already hardlinked are not counted as duplicates
+fn collapse_hardlinks(mut file_entries: Vec<FileEntry>) -> Vec<FileEntry> {
+    file_entries.sort_by(|a, b| a.path.cmp(&b.path));
+    let mut seen = HashSet::new();
+    file_entries.retain(|entry| match entry.inode {
+        Some(inode) => seen.insert(inode),
+        None => true,
+    });
+    file_entries
+}
+
+// Computes the digests of the files and sorts them by digest, ready for
+// grouping.
+fn digest_files(file_entries: Vec<FileEntry>) -> Vec<FileEntry> {
+    digest_files_timed(file_entries, &mut Timings::default())
+}
+
+fn digest_files_timed(mut file_entries: Vec<FileEntry>, timings: &mut Timings) -> Vec<FileEntry> {
     let file_count = file_entries.len();
     let file_bytes = file_entries.iter().fold(0, |acc, entry| acc + entry.len);
 
-    println!("Have {} files with {} bytes", file_count, file_bytes);
+    eprintln!(
+        "Have {} files with {}",
+        file_count,
+        format_bytes(file_bytes)
+    );
+
+    eprintln!("Compute digests");
+    timed(&mut timings.hash_duration, || {
+        compute_digests(&mut file_entries)
+    });
+
+    eprintln!("Sorting by digest");
+    let start = Instant::now();
+    timed(&mut timings.sort_duration, || {
+        // Sort by path within each digest, for a reproducible order of the
+        // files in each group
+        file_entries.par_sort_unstable_by(|a, b| {
+            (a.digest.load(), &a.path).cmp(&(b.digest.load(), &b.path))
+        })
+    });
+    log::info!(
+        "phase=sort entries={} elapsed={:?}",
+        file_entries.len(),
+        start.elapsed()
+    );
+    file_entries
+}
 
-    println!("Compute digests");
-    compute_digests(&mut file_entries);
+// Compares the contents of two files byte by byte
+fn contents_equal(a: &Path, b: &Path) -> io::Result<bool> {
+    let mut a = io::BufReader::new(std::fs::File::open(a)?);
+    let mut b = io::BufReader::new(std::fs::File::open(b)?);
+    loop {
+        let (a_buf, b_buf) = (a.fill_buf()?, b.fill_buf()?);
+        let len = a_buf.len().min(b_buf.len());
+        if len == 0 {
+            return Ok(a_buf.is_empty() && b_buf.is_empty());
+        }
+        if a_buf[..len] != b_buf[..len] {
+            return Ok(false);
+        }
+        a.consume(len);
+        b.consume(len);
+    }
+}
 
-    println!("Sorting by digest");
-    file_entries.par_sort_unstable_by(|a, b| a.digest.load().cmp(&b.digest.load()));
+// Strong comparison for files with equal digests: the sizes must match,
+// and then the contents. Unreadable files are never identical.
+fn identical(a: &FileEntry, b: &FileEntry) -> bool {
+    a.len == b.len && contents_equal(&a.path, &b.path).unwrap_or(false)
+}
 
-    let groups_it = group_by_digest(&file_entries);
+// Splits a group of files with equal digests where neighbouring files are
+// not identical. Should digests ever collide, files which differ are then
+// never grouped; at worst some real duplicates end up in separate groups.
+fn split_non_identical(group: &[FileEntry]) -> Vec<&[FileEntry]> {
+    let mut groups = Vec::new();
+    let mut begin = 0;
+    for i in 1..group.len() {
+        if !identical(&group[i - 1], &group[i]) {
+            groups.push(&group[begin..i]);
+            begin = i;
+        }
+    }
+    groups.push(&group[begin..]);
+    groups
+}
 
-    for g in groups_it.filter(|x| x.len() > 10).take(10) {
-        let mut pk_g = g.iter().peekable();
-        let first = *pk_g.peek().unwrap();
-        println!("");
-        println!(
-            "Group: {} file count {} file size {} digest {:?}",
-            first.name,
-            g.len(),
-            first.len,
-            first.digest.load()
-        );
-        println!("Files:");
-        for file in pk_g {
-            println!("   {}", file.path.to_str().unwrap());
+// Splits a group of identical files into groups with equal permissions,
+// each still in path order. Hardlinked files share one mode, so linking
+// across modes would change the permissions of some of the paths.
+fn split_by_mode(group: &[FileEntry]) -> Vec<Vec<FileEntry>> {
+    let mut groups: Vec<Vec<FileEntry>> = Vec::new();
+    for entry in group {
+        match groups.iter_mut().find(|g| g[0].mode == entry.mode) {
+            Some(mode_group) => mode_group.push(entry.clone()),
+            None => groups.push(vec![entry.clone()]),
         }
     }
+    groups
+}
+
+// Groups of identical files, from entries sorted by digest_files(). With
+// verify_contents, files are also compared byte by byte instead of trusting
+// the digests.
+fn duplicate_groups(file_entries: &[FileEntry], verify_contents: bool) -> Vec<&[FileEntry]> {
+    let groups = par_group_by_digest(file_entries)
+        .into_iter()
+        .filter(|group| group.len() > 1);
+    if verify_contents {
+        groups
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .flat_map(split_non_identical)
+            .filter(|group| group.len() > 1)
+            .collect()
+    } else {
+        groups.collect()
+    }
+}
+
+fn compute_savings(file_entries: &[FileEntry], verify_contents: bool) -> Savings {
+    let file_count = file_entries.len();
+    let file_bytes = file_entries.iter().fold(0, |acc, entry| acc + entry.len);
+    let groups = duplicate_groups(file_entries, verify_contents);
+    savings_from_groups(file_count, file_bytes, &groups)
+}
 
-    println!("Duped  : {} bytes", file_bytes);
-    //    println!("Deduped: {} bytes", dedup_bytes);
-    println!("files : {}", file_count);
-    //println!("groups: {}", group_count);
+// Savings for the duplicate groups found among file_count files, of
+// file_bytes in total
+fn savings_from_groups(file_count: usize, file_bytes: u64, groups: &[&[FileEntry]]) -> Savings {
+    let mut duplicate_groups: Vec<SavingsGroup> = groups
+        .iter()
+        .map(|group| SavingsGroup::from_group(group))
+        .collect();
+    // Hardlinked paths have identical contents, so they always are in the
+    // same group. Their bytes are on disk only once, before and after dedup,
+    // and groups of links to a single file have nothing to reclaim.
+    let linked_bytes: u64 = duplicate_groups.iter().map(|g| g.linked_bytes()).sum();
+    duplicate_groups.retain(|group| group.file_count > 1);
+    duplicate_groups.sort_by_key(|group| std::cmp::Reverse(group.reclaimable_bytes()));
 
     //Crate histogram
 
@@ -355,94 +836,2370 @@ fn compute_savings(entries: Vec<JWalkDirEntry>) {
             4
         });
     */
+
+    let reclaimable_bytes = duplicate_groups.iter().map(|g| g.reclaimable_bytes()).sum();
+    let disk_bytes = file_bytes - linked_bytes;
+    let deduped_bytes = disk_bytes - reclaimable_bytes;
+    Savings {
+        file_count,
+        total_bytes: file_bytes,
+        reclaimable_bytes,
+        deduped_bytes,
+        dedup_ratio: if disk_bytes == 0 {
+            1.0
+        } else {
+            deduped_bytes as f64 / disk_bytes as f64
+        },
+        group_count: duplicate_groups.len(),
+        top_groups: duplicate_groups.into_iter().take(TOP_GROUP_COUNT).collect(),
+        timings: Timings::default(),
+    }
 }
 
-fn main() {
-    let scan = App::new("scan")
-        .about("scan folder for files")
-        .arg(Arg::new("path").about("Specifies filesystem path"))
-        .arg(
-            Arg::new("save")
-                .short('s')
-                .long("--save")
-                .about("Save file list to disk"),
-        );
-    let compute = App::new("compute")
-        .about("compute (potential) dedup savings")
-        .arg(Arg::new("path").about("Specifies filesystem path"))
-        .arg(
-            Arg::new("load")
-                .short('l')
-                .long("--load")
-                .about("Load file list from disk"),
-        );
-    let dedup = App::new("dedup")
-        .about("deduplicate files")
-        .arg(Arg::new("path").about("Specifies filesystem path"))
-        .arg(
-            Arg::new("load")
-                .short('l')
-                .long("--load")
-                .about("Load file list from disk"),
-        );
+// Walks the same files as filter_files(scan_dir2(path)), one at a time and
+// without progress output
+fn walk_files<'a>(
+    path: &str,
+    errors: &'a mut Vec<jwalk::Error>,
+) -> impl Iterator<Item = FileEntry> + 'a {
+    WalkDir::new(path)
+        .follow_links(false)
+        .min_depth(2) // scan_dir2 scans the subdirectories of path
+        .into_iter()
+        .filter_map(move |result| take_walk_error(result, errors))
+        .filter_map(|entry| FileEntry::from_jwalk_entry(&entry))
+        .filter(|entry| entry.len > MIN_FILE_SIZE)
+}
 
-    let matches = App::new("llvmbuilder")
-        .subcommand(scan)
-        .subcommand(compute)
-        .subcommand(dedup)
-        .get_matches();
+// Computes the savings for the files under path, without keeping an entry for
+// every file in memory. A first pass counts the file sizes; the second pass
+// collects and hashes only the files whose size is shared by another file.
+fn streaming_savings(path: &str, verify_contents: bool) -> Savings {
+    let mut timings = Timings::default();
+    let mut file_count = 0;
+    let mut file_bytes = 0;
+    let mut size_counts = HashMap::<u64, usize>::new();
+    let mut errors = Vec::new();
+    timed(&mut timings.walk_duration, || {
+        for entry in walk_files(path, &mut errors) {
+            file_count += 1;
+            file_bytes += entry.len;
+            *size_counts.entry(entry.len).or_insert(0) += 1;
+        }
+    });
+    for err in &errors {
+        eprintln!("Skipped: {}", err);
+    }
 
-    //println!("helo, {:?}", matches);
+    let candidates: Vec<FileEntry> = timed(&mut timings.walk_duration, || {
+        walk_files(path, &mut Vec::new())
+            .filter(|entry| matches!(size_counts.get(&entry.len), Some(&count) if count > 1))
+            .collect()
+    });
+    drop(size_counts);
 
-    match matches.subcommand() {
-        Some(("scan", args)) => {
-            println!("{:?}", args);
-            match args.value_of("path") {
-                Some(path) => {
-                    println!("scan {:?}", path);
-                    let entries = scan_dir2(path);
-                    println!("scan found {:?} files", entries.len());
+    let candidates = digest_files_timed(candidates, &mut timings);
+    let groups = duplicate_groups(&candidates, verify_contents);
+    let mut savings = savings_from_groups(file_count, file_bytes, &groups);
+    savings.timings = timings;
+    savings
+}
 
-                    if args.is_present("save") {
-                        let filified_path = path.replace("/", "-");
-                        let file_name = "dedupfiles".to_string() + &filified_path;
-                        println!("Saving file list to {}", file_name);
-                        /*
-                                                match bincode::serialize(&entries) {
-                                                    Err(err) => panic!("serialize_digests error {:?}", err),
-                                                    Ok(vec) => return vec,
-                                                }
-                        */
-                    }
-                }
-                None => {
-                    println!("Missing path argument");
-                }
-            }
+#[cfg(unix)]
+fn path_to_bytes(path: &Path) -> std::borrow::Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().into()
+}
+
+#[cfg(not(unix))]
+fn path_to_bytes(path: &Path) -> std::borrow::Cow<'_, [u8]> {
+    match path.to_string_lossy() {
+        std::borrow::Cow::Borrowed(s) => s.as_bytes().into(),
+        std::borrow::Cow::Owned(s) => s.into_bytes().into(),
+    }
+}
+
+// Writes the paths in each group followed by `terminator`, with an empty
+// record after each group if `separate_groups` is set. Paths are written as
+// their exact bytes, so with a NUL terminator any file name survives the trip
+// through a pipe.
+fn write_groups<W: Write>(
+    groups: &[&[FileEntry]],
+    mut output: W,
+    terminator: u8,
+    separate_groups: bool,
+) -> io::Result<()> {
+    for group in groups {
+        for entry in group.iter() {
+            output.write_all(&path_to_bytes(&entry.path))?;
+            output.write_all(&[terminator])?;
         }
-        Some(("compute", args)) => {
-            println!("compute");
-            match args.value_of("path") {
-                Some(path) => {
-                    println!("compute {:?}", path);
-                    let entries = scan_dir2(path);
-                    compute_savings(entries);
-                    //find_candidates(path, 1);
-                }
-                None => {
-                    println!("Missing path argument");
-                }
-            }
+        if separate_groups {
+            output.write_all(&[terminator])?;
         }
-        Some(("dedup", _args)) => {
-            println!("dedup");
+    }
+    output.flush()
+}
+
+fn print_savings(savings: &Savings) {
+    for group in &savings.top_groups {
+        println!("");
+        println!(
+            "Group: file count {} file size {} digest {}",
+            group.paths.len(),
+            format_bytes(group.size),
+            group.digest
+        );
+        println!("Files:");
+        for path in &group.paths {
+            println!("   {}", path);
         }
+    }
 
-        Some((command, _args)) => {
-            println!("Unknownn command: {:}", command);
+    println!("Duped  : {}", format_bytes(savings.total_bytes));
+    println!(
+        "Deduped: {} ({:.1}% saved)",
+        format_bytes(savings.deduped_bytes),
+        (1.0 - savings.dedup_ratio) * 100.0
+    );
+    println!("files : {}", savings.file_count);
+    println!("groups: {}", savings.group_count);
+    println!(
+        "time  : walk {:.2?} hash {:.2?} sort {:.2?}",
+        savings.timings.walk_duration, savings.timings.hash_duration, savings.timings.sort_duration
+    );
+}
+
+// Picks the index of the file to keep in a group of identical files
+type KeeperPolicy = Box<dyn FnMut(&[FileEntry]) -> usize>;
+
+#[derive(Default)]
+struct DedupOptions {
+    // Keep the default file in each group without asking
+    assume_yes: bool,
+    // Files under these paths are never replaced, and are always kept.
+    // In canonical form, see canonical_prefixes().
+    protected: Vec<PathBuf>,
+    // Compare file contents instead of trusting equal digests
+    verify_contents: bool,
+    // Only link files with equal permissions
+    respect_perms: bool,
+    // Picks the file to keep by default, in place of default_keeper().
+    // Protected files are still kept first.
+    keeper_policy: Option<KeeperPolicy>,
+}
+
+impl DedupOptions {
+    fn is_protected(&self, path: &Path) -> bool {
+        is_under(path, &self.protected)
+    }
+
+    fn default_keeper(&mut self, group: &[FileEntry]) -> io::Result<usize> {
+        match &mut self.keeper_policy {
+            Some(policy) => {
+                let keeper = policy(group);
+                if keeper >= group.len() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "keeper policy picked file {} in a group of {}",
+                            keeper,
+                            group.len()
+                        ),
+                    ));
+                }
+                Ok(keeper)
+            }
+            None => Ok(default_keeper(group)),
         }
-        None => {
-            println!("Missing command");
+    }
+}
+
+// True if the path is under one of the prefixes, which must be in canonical
+// form. Paths are compared in canonical form, since the scan root and the
+// prefixes given on the command line may be spelled differently.
+fn is_under(path: &Path, prefixes: &[PathBuf]) -> bool {
+    let path = canonical_path(path);
+    prefixes.iter().any(|prefix| path.starts_with(prefix))
+}
+
+// Canonical form of path prefixes for is_under(). Done once, when the
+// options are built, rather than for every file compared.
+fn canonical_prefixes(prefixes: Vec<PathBuf>) -> Vec<PathBuf> {
+    prefixes
+        .into_iter()
+        .map(|prefix| {
+            prefix
+                .canonicalize()
+                .unwrap_or_else(|_| canonical_path(&prefix))
+        })
+        .collect()
+}
+
+// Absolute form of a path, with `.`, `..` and symlinked directories resolved.
+// The file name itself is kept, so a symlink is not replaced by its target.
+fn canonical_path(path: &Path) -> PathBuf {
+    if let (Some(parent), Some(name)) = (path.parent(), path.file_name()) {
+        let parent = if parent.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            parent
+        };
+        if let Ok(parent) = parent.canonicalize() {
+            return parent.join(name);
+        }
+    }
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+// The file kept when the user doesn't choose one: the first path in sort order
+fn default_keeper(group: &[FileEntry]) -> usize {
+    let mut keeper = 0;
+    for (i, entry) in group.iter().enumerate() {
+        if entry.path < group[keeper].path {
+            keeper = i;
+        }
+    }
+    keeper
+}
+
+// Keeps the first file in sort order under one of the preferred paths, for
+// example a preferred mount. Groups without such files use default_keeper().
+fn prefer_paths(preferred: Vec<PathBuf>) -> KeeperPolicy {
+    let preferred = canonical_prefixes(preferred);
+    Box::new(move |group: &[FileEntry]| {
+        (0..group.len())
+            .filter(|&i| is_under(&group[i].path, &preferred))
+            .min_by(|&a, &b| group[a].path.cmp(&group[b].path))
+            .unwrap_or_else(|| default_keeper(group))
+    })
+}
+
+// Like default_keeper(), but only considers protected files. Returns None if
+// the group has no protected files.
+fn protected_keeper(group: &[FileEntry], options: &DedupOptions) -> Option<usize> {
+    (0..group.len())
+        .filter(|&i| options.is_protected(&group[i].path))
+        .min_by(|&a, &b| group[a].path.cmp(&group[b].path))
+}
+
+// Asks which file to keep for each group of identical files. Returns the
+// index of the file to keep per group, or None to leave the group alone.
+fn select_keepers<R: BufRead, W: Write>(
+    groups: &[&[FileEntry]],
+    mut input: R,
+    mut output: W,
+    options: &mut DedupOptions,
+) -> io::Result<Vec<Option<usize>>> {
+    let mut keepers = Vec::with_capacity(groups.len());
+    for group in groups {
+        // Groups with protected files always keep one of those, and groups
+        // with only protected files are left alone
+        if let Some(keeper) = protected_keeper(group, options) {
+            let all_protected = group.iter().all(|e| options.is_protected(&e.path));
+            keepers.push(if all_protected { None } else { Some(keeper) });
+            continue;
+        }
+
+        let default = options.default_keeper(group)?;
+        if options.assume_yes {
+            keepers.push(Some(default));
+            continue;
+        }
+
+        writeln!(output)?;
+        writeln!(
+            output,
+            "{} identical files of {}:",
+            group.len(),
+            format_bytes(group[0].len)
+        )?;
+        for (i, entry) in group.iter().enumerate() {
+            writeln!(output, "  [{}] {}", i + 1, entry.path.display())?;
+        }
+
+        let keeper = loop {
+            write!(
+                output,
+                "Keep which file? [1-{}, s to skip, default {}] ",
+                group.len(),
+                default + 1
+            )?;
+            output.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                break None; // end of input: leave the remaining groups alone
+            }
+            match line.trim() {
+                "" => break Some(default),
+                "s" => break None,
+                choice => match choice.parse::<usize>() {
+                    Ok(n) if (1..=group.len()).contains(&n) => break Some(n - 1),
+                    _ => writeln!(output, "Invalid choice {:?}", choice)?,
+                },
+            }
+        };
+        keepers.push(keeper);
+    }
+    Ok(keepers)
+}
+
+// Replaces `duplicate` with a hardlink to `keeper`. The link is created under
+// a temporary name and renamed over the duplicate, so that the duplicate path
+// never goes missing.
+// Returns false if the duplicate already was a link to the keeper.
+fn link_duplicate(keeper: &Path, duplicate: &Path) -> io::Result<bool> {
+    // Renaming a link over another link to the same file does nothing, and
+    // would leave the temporary link behind
+    if same_file(keeper, duplicate)? {
+        return Ok(false);
+    }
+    let mut temp_name = duplicate.file_name().unwrap().to_os_string();
+    temp_name.push(".dedup-tmp");
+    let temp_path = duplicate.with_file_name(temp_name);
+
+    std::fs::hard_link(keeper, &temp_path)?;
+    if let Err(err) = std::fs::rename(&temp_path, duplicate) {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(err);
+    }
+    Ok(true)
+}
+
+// True if both paths are links to the same file
+fn same_file(a: &Path, b: &Path) -> io::Result<bool> {
+    let a = inode_of(&std::fs::metadata(a)?);
+    Ok(a.is_some() && a == inode_of(&std::fs::metadata(b)?))
+}
+
+// True if all files in the group already are links to the same file
+fn already_linked(group: &[FileEntry]) -> bool {
+    group[0].inode.is_some() && group.iter().all(|entry| entry.inode == group[0].inode)
+}
+
+// Paths in saved plans are strings where they are valid UTF-8, which keeps the
+// plans readable, and raw bytes otherwise, so that any path round trips
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SerializedPath {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+fn serialize_path<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    match path.to_str() {
+        Some(text) => serializer.serialize_str(text),
+        None => serializer.collect_seq(path_to_bytes(path).iter()),
+    }
+}
+
+fn deserialize_path<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+    Ok(match SerializedPath::deserialize(deserializer)? {
+        SerializedPath::Text(text) => PathBuf::from(text),
+        SerializedPath::Bytes(bytes) => path_from_bytes(bytes),
+    })
+}
+
+// A file referenced by a dedup plan, with the size and digest it had when
+// the plan was made. Paths are absolute, so that the plan can be applied
+// from another directory.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct PlannedFile {
+    #[serde(
+        serialize_with = "serialize_path",
+        deserialize_with = "deserialize_path"
+    )]
+    path: PathBuf,
+    len: u64,
+    digest: String,
+}
+
+impl PlannedFile {
+    fn from_entry(entry: &FileEntry) -> PlannedFile {
+        PlannedFile {
+            path: canonical_path(&entry.path),
+            len: entry.len,
+            digest: digest_hex(entry.load_digest()),
+        }
+    }
+
+    // Checks that the file is unchanged since planning. Comparing digests
+    // reads the whole file, so that check is optional.
+    fn check(&self, verify_digest: bool) -> Option<PlanConflict> {
+        let metadata = match std::fs::symlink_metadata(&self.path) {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => return Some(PlanConflict::Missing(self.path.clone())),
+        };
+        if metadata.len() != self.len {
+            return Some(PlanConflict::SizeChanged {
+                path: self.path.clone(),
+                planned: self.len,
+                actual: metadata.len(),
+            });
+        }
+        if verify_digest {
+            match try_file_digest(&self.path) {
+                Ok(digest) if digest_hex(digest) == self.digest => {}
+                Ok(_) => return Some(PlanConflict::DigestChanged(self.path.clone())),
+                Err(err) => return Some(PlanConflict::Unreadable(self.path.clone(), err.kind())),
+            }
+        }
+        None
+    }
+}
+
+// A planned file which has changed since the plan was made, or which can't
+// be linked to the file kept in its group
+#[derive(Debug, PartialEq, Eq)]
+enum PlanConflict {
+    Missing(PathBuf),
+    SizeChanged {
+        path: PathBuf,
+        planned: u64,
+        actual: u64,
+    },
+    DigestChanged(PathBuf),
+    Unreadable(PathBuf, io::ErrorKind),
+    // Planned with another size or digest than the file to keep, as in a
+    // plan that was edited by hand
+    Mismatched {
+        path: PathBuf,
+        keep: PathBuf,
+    },
+}
+
+impl PlanConflict {
+    fn path(&self) -> &Path {
+        match self {
+            PlanConflict::Missing(path)
+            | PlanConflict::SizeChanged { path, .. }
+            | PlanConflict::DigestChanged(path)
+            | PlanConflict::Unreadable(path, _)
+            | PlanConflict::Mismatched { path, .. } => path,
+        }
+    }
+}
+
+impl fmt::Display for PlanConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlanConflict::Missing(path) => write!(f, "{} is missing", path.display()),
+            PlanConflict::SizeChanged {
+                path,
+                planned,
+                actual,
+            } => write!(
+                f,
+                "{} changed size from {} to {} bytes",
+                path.display(),
+                planned,
+                actual
+            ),
+            PlanConflict::DigestChanged(path) => {
+                write!(f, "{} changed content", path.display())
+            }
+            PlanConflict::Unreadable(path, kind) => {
+                write!(
+                    f,
+                    "{} can't be read: {}",
+                    path.display(),
+                    io::Error::from(*kind)
+                )
+            }
+            PlanConflict::Mismatched { path, keep } => {
+                write!(f, "{} doesn't match {}", path.display(), keep.display())
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct PlannedGroup {
+    keep: PlannedFile,
+    // Files to replace with hardlinks to `keep`
+    link: Vec<PlannedFile>,
+}
+
+impl PlannedGroup {
+    fn conflicts(&self, verify_digests: bool) -> Vec<PlanConflict> {
+        let mismatched = self
+            .link
+            .iter()
+            .filter(|file| file.len != self.keep.len || file.digest != self.keep.digest)
+            .map(|file| PlanConflict::Mismatched {
+                path: file.path.clone(),
+                keep: self.keep.path.clone(),
+            });
+        let changed = std::iter::once(&self.keep)
+            .chain(&self.link)
+            .filter_map(|file| file.check(verify_digests));
+        mismatched.chain(changed).collect()
+    }
+}
+
+// The actions of a dedup run. The plan can be saved and applied later,
+// which separates the (expensive) analysis from the modifications, and
+// makes it possible to review or edit the plan in between.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+struct DedupPlan {
+    groups: Vec<PlannedGroup>,
+}
+
+impl DedupPlan {
+    fn load(path: &Path) -> io::Result<DedupPlan> {
+        let reader = io::BufReader::new(std::fs::File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    // The plan is written under a temporary name and renamed into place, so
+    // that a failed save never leaves a truncated plan behind
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let mut temp_path = path.as_os_str().to_os_string();
+        temp_path.push(".dedup-tmp");
+        let temp_path = PathBuf::from(temp_path);
+
+        if let Err(err) = self
+            .write(&temp_path)
+            .and_then(|()| std::fs::rename(&temp_path, path))
+        {
+            let _ = std::fs::remove_file(&temp_path);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    fn write(&self, path: &Path) -> io::Result<()> {
+        let mut writer = io::BufWriter::new(std::fs::File::create(path)?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.flush()
+    }
+
+    // Bytes the plan would reclaim when applied
+    fn reclaimable_bytes(&self) -> u64 {
+        self.groups
+            .iter()
+            .flat_map(|group| &group.link)
+            .map(|file| file.len)
+            .sum()
+    }
+
+    // Returns the planned files which no longer match the file system
+    fn validate(&self, verify_digests: bool) -> Vec<PlanConflict> {
+        self.groups
+            .iter()
+            .flat_map(|group| group.conflicts(verify_digests))
+            .collect()
+    }
+}
+
+// Selects the file to keep in each group of identical files, and plans to
+// replace the others with hardlinks to it.
+fn plan_dedup<R: BufRead, W: Write>(
+    file_entries: &[FileEntry],
+    input: R,
+    output: W,
+    options: &mut DedupOptions,
+) -> io::Result<DedupPlan> {
+    let mode_groups: Vec<Vec<FileEntry>>;
+    let mut groups = duplicate_groups(file_entries, options.verify_contents);
+    if options.respect_perms {
+        mode_groups = groups
+            .into_iter()
+            .flat_map(split_by_mode)
+            .filter(|group| group.len() > 1)
+            .collect();
+        groups = mode_groups.iter().map(Vec::as_slice).collect();
+    }
+    // Nothing to reclaim from files which were linked by an earlier run
+    groups.retain(|group| !already_linked(group));
+    let keepers = select_keepers(&groups, input, output, options)?;
+
+    let mut plan = DedupPlan::default();
+    for (group, keeper) in groups.iter().zip(keepers) {
+        let keeper = match keeper {
+            Some(index) => &group[index],
+            None => continue,
+        };
+        let link: Vec<PlannedFile> = group
+            .iter()
+            .filter(|entry| entry.path != keeper.path && !options.is_protected(&entry.path))
+            .filter(|entry| entry.inode.is_none() || entry.inode != keeper.inode)
+            .map(PlannedFile::from_entry)
+            .collect();
+        if !link.is_empty() {
+            plan.groups.push(PlannedGroup {
+                keep: PlannedFile::from_entry(keeper),
+                link,
+            });
+        }
+    }
+    Ok(plan)
+}
+
+// Performs the planned hardlinking. Returns the number of bytes reclaimed.
+// Groups with files that changed since planning are skipped, since linking
+// them could destroy the new content. The whole plan is validated before
+// anything is linked, so that all conflicts are reported up front.
+// Files under the (canonical) protected paths are never replaced, whatever
+// the plan says.
+fn apply_plan<W: Write>(
+    plan: &DedupPlan,
+    verify_digests: bool,
+    protected: &[PathBuf],
+    mut output: W,
+) -> io::Result<u64> {
+    let start = Instant::now();
+    let conflicts = plan.validate(verify_digests);
+    for conflict in &conflicts {
+        writeln!(output, "Skipping group: {}", conflict)?;
+    }
+    let conflicted: HashSet<&Path> = conflicts.iter().map(PlanConflict::path).collect();
+
+    let mut reclaimed_bytes = 0;
+    let mut linked = 0;
+    for group in &plan.groups {
+        let mut files = std::iter::once(&group.keep).chain(&group.link);
+        if files.any(|file| conflicted.contains(file.path.as_path())) {
+            continue;
+        }
+        for file in &group.link {
+            if is_under(&file.path, protected) {
+                writeln!(output, "Skipping protected {}", file.path.display())?;
+                continue;
+            }
+            match link_duplicate(&group.keep.path, &file.path) {
+                Ok(true) => {
+                    linked += 1;
+                    reclaimed_bytes += file.len;
+                }
+                Ok(false) => {}
+                Err(err) => writeln!(output, "Failed to link {}: {}", file.path.display(), err)?,
+            }
+        }
+    }
+    log::info!(
+        "phase=dedup entries={} conflicts={} bytes={} elapsed={:?}",
+        linked,
+        conflicts.len(),
+        reclaimed_bytes,
+        start.elapsed()
+    );
+    Ok(reclaimed_bytes)
+}
+
+// Writes groups in the fdupes output format: one path per line, with an empty
+// line after each group
+fn write_fdupes<W: Write>(groups: &[&[FileEntry]], output: W) -> io::Result<()> {
+    write_groups(groups, output, b'\n', true)
+}
+
+// Plans to keep the first file and link the others. The files are hashed
+// here, since the paths may come from another tool. Such lists may be out of
+// date, so missing, unreadable and non-regular files are errors.
+fn plan_group_from_paths(paths: &[PathBuf]) -> io::Result<PlannedGroup> {
+    let mut files = Vec::new();
+    for path in paths {
+        let with_path =
+            |err: io::Error| io::Error::new(err.kind(), format!("{}: {}", path.display(), err));
+        let metadata = std::fs::symlink_metadata(path).map_err(with_path)?;
+        if !metadata.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: not a regular file", path.display()),
+            ));
+        }
+        let digest = try_file_digest(path).map_err(with_path)?;
+        files.push(PlannedFile {
+            path: canonical_path(path),
+            len: metadata.len(),
+            digest: digest_hex(digest),
+        });
+    }
+    if files.iter().any(|file| file.digest != files[0].digest) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("files differ in group of {}", paths[0].display()),
+        ));
+    }
+    let keep = files.remove(0);
+    Ok(PlannedGroup { keep, link: files })
+}
+
+// Reads duplicate groups in the fdupes output format, as written by fdupes
+// or write_fdupes(), and plans to keep the first file in each group
+fn read_fdupes<R: BufRead>(input: R) -> io::Result<DedupPlan> {
+    let mut plan = DedupPlan::default();
+    let mut paths = Vec::new();
+    // The trailing empty line ends the last group
+    for line in input.split(b'\n').chain(Some(Ok(Vec::new()))) {
+        let line = line?;
+        if !line.is_empty() {
+            paths.push(path_from_bytes(line));
+            continue;
+        }
+        if paths.len() > 1 {
+            plan.groups.push(plan_group_from_paths(&paths)?);
+        }
+        paths.clear();
+    }
+    Ok(plan)
+}
+
+// Prints the planned actions without performing them. Returns the number of
+// bytes the plan would reclaim.
+fn print_plan<W: Write>(plan: &DedupPlan, mut output: W) -> io::Result<u64> {
+    for group in &plan.groups {
+        writeln!(output, "Keep {}", group.keep.path.display())?;
+        for file in &group.link {
+            writeln!(output, "  link {}", file.path.display())?;
+        }
+    }
+    let bytes = plan.reclaimable_bytes();
+    writeln!(output, "Would reclaim {}", format_bytes(bytes))?;
+    Ok(bytes)
+}
+
+// Loads a saved digest file and computes its statistics, without scanning
+fn inspect_digest_file(path: &Path) -> Result<(Header, Inventory), EntriesFileError> {
+    let (header, table) = persistence::load_with_header(path)?;
+    Ok((header, Inventory::from_table(&table)))
+}
+
+fn write_inventory<W: Write>(
+    header: &Header,
+    inventory: &Inventory,
+    mut output: W,
+) -> io::Result<()> {
+    writeln!(output, "format: {}", header)?;
+    writeln!(output, "files : {}", inventory.file_count)?;
+    writeln!(output, "size  : {}", format_bytes(inventory.total_bytes))?;
+    writeln!(
+        output,
+        "same-size files: {} sizes, at most {} reclaimable",
+        inventory.candidate_sizes.len(),
+        format_bytes(inventory.max_reclaimable_bytes())
+    )?;
+    for &(size, count) in inventory.candidate_sizes.iter().take(TOP_GROUP_COUNT) {
+        writeln!(output, "   {} files of {}", count, format_bytes(size))?;
+    }
+    writeln!(output, "by extension:")?;
+    for (extension, count, bytes) in inventory.extensions.iter().take(TOP_GROUP_COUNT) {
+        let extension = if extension.is_empty() {
+            "(none)"
+        } else {
+            extension
+        };
+        writeln!(
+            output,
+            "   {}: {} files, {}",
+            extension,
+            count,
+            format_bytes(*bytes)
+        )?;
+    }
+    output.flush()
+}
+
+// The files to process: either a scan of the path argument, or the paths
+// listed on stdin.
+fn input_files(args: &ArgMatches) -> Option<Vec<FileEntry>> {
+    if args.is_present("from-stdin") {
+        let separator = if args.is_present("null") {
+            b'\0'
+        } else {
+            b'\n'
+        };
+        match read_file_list(io::stdin().lock(), separator) {
+            Ok(file_entries) => Some(file_entries),
+            Err(err) => {
+                println!("Failed to read file list: {}", err);
+                None
+            }
+        }
+    } else {
+        match args.value_of("path") {
+            Some(path) => {
+                eprintln!("scan {:?}", path);
+                Some(filter_files(scan_dir2(path)))
+            }
+            None => {
+                println!("Missing path argument");
+                None
+            }
+        }
+    }
+}
+
+fn main() {
+    let scan = App::new("scan")
+        .about("scan folder for files")
+        .arg(Arg::new("path").about("Specifies filesystem path"))
+        .arg(
+            Arg::new("save")
+                .short('s')
+                .long("--save")
+                .about("Save file list to disk"),
+        );
+    let compute = App::new("compute")
+        .about("compute (potential) dedup savings")
+        .arg(Arg::new("path").about("Specifies filesystem path"))
+        .arg(
+            Arg::new("load")
+                .short('l')
+                .long("--load")
+                .about("Load file list from disk"),
+        )
+        .arg(
+            Arg::new("json")
+                .long("--json")
+                .about("Print results as JSON"),
+        )
+        .arg(
+            Arg::new("print0")
+                .long("--print0")
+                .about("Print the paths of duplicate files, NUL-terminated"),
+        )
+        .arg(
+            Arg::new("fdupes")
+                .long("--fdupes")
+                .about("Print the duplicate groups in fdupes format"),
+        )
+        .arg(
+            Arg::new("collapse-hardlinks")
+                .long("--collapse-hardlinks")
+                .about("Count files which are hardlinked to each other once"),
+        )
+        .arg(
+            Arg::new("streaming")
+                .long("--streaming")
+                .about("Only keep files with duplicate sizes in memory (report only)"),
+        )
+        .arg(
+            Arg::new("verify")
+                .long("--verify")
+                .about("Compare file contents byte by byte, not only digests"),
+        )
+        .arg(
+            Arg::new("from-stdin")
+                .long("--from-stdin")
+                .about("Read the files to process from stdin instead of scanning"),
+        )
+        .arg(
+            Arg::new("null")
+                .short('0')
+                .long("--null")
+                .about("File names read from stdin are NUL-separated"),
+        );
+    let dedup = App::new("dedup")
+        .about("deduplicate files")
+        .arg(Arg::new("path").about("Specifies filesystem path"))
+        .arg(
+            Arg::new("load")
+                .short('l')
+                .long("--load")
+                .about("Load file list from disk"),
+        )
+        .arg(
+            Arg::new("yes")
+                .short('y')
+                .long("--yes")
+                .about("Keep the default file in each group without asking"),
+        )
+        .arg(
+            Arg::new("protect")
+                .short('p')
+                .long("--protect")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .about("Never modify files under this path, keep them instead"),
+        )
+        .arg(
+            Arg::new("prefer")
+                .long("--prefer")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .conflicts_with_all(&["apply-plan", "from-fdupes"])
+                .about("Keep the file under this path by default, if a group has one"),
+        )
+        .arg(
+            Arg::new("verify")
+                .long("--verify")
+                .conflicts_with_all(&["apply-plan", "from-fdupes"])
+                .about("Compare file contents byte by byte, not only digests"),
+        )
+        .arg(
+            Arg::new("respect-perms")
+                .long("--respect-perms")
+                .conflicts_with_all(&["apply-plan", "from-fdupes"])
+                .about("Never link files with different permissions"),
+        )
+        .arg(
+            Arg::new("save-plan")
+                .long("--save-plan")
+                .takes_value(true)
+                .about("Save the planned actions to a file instead of applying them"),
+        )
+        .arg(
+            Arg::new("apply-plan")
+                .long("--apply-plan")
+                .takes_value(true)
+                .about("Apply actions from a saved plan instead of scanning"),
+        )
+        .arg(
+            Arg::new("from-fdupes")
+                .long("--from-fdupes")
+                .takes_value(true)
+                .about("Plan from duplicate groups in fdupes format, keeping the first file"),
+        )
+        .arg(
+            Arg::new("dry-run")
+                .long("--dry-run")
+                .conflicts_with("apply")
+                .about("Print the planned actions without modifying files (default)"),
+        )
+        .arg(
+            Arg::new("apply")
+                .long("--apply")
+                .about("Perform the planned actions"),
+        )
+        .arg(
+            Arg::new("from-stdin")
+                .long("--from-stdin")
+                .about("Read the files to process from stdin instead of scanning"),
+        )
+        .arg(
+            Arg::new("null")
+                .short('0')
+                .long("--null")
+                .about("File names read from stdin are NUL-separated"),
+        );
+
+    let inspect = App::new("inspect")
+        .about("show statistics for a saved digest file")
+        .arg(
+            Arg::new("digest-file")
+                .required(true)
+                .about("Specifies the digest file"),
+        );
+
+    let matches = App::new("llvmbuilder")
+        .subcommand(scan)
+        .subcommand(compute)
+        .subcommand(dedup)
+        .subcommand(inspect)
+        .get_matches();
+
+    //println!("helo, {:?}", matches);
+
+    match matches.subcommand() {
+        Some(("scan", args)) => {
+            println!("{:?}", args);
+            match args.value_of("path") {
+                Some(path) => {
+                    println!("scan {:?}", path);
+                    let entries = scan_dir2(path);
+                    println!("scan found {:?} files", entries.len());
+
+                    if args.is_present("save") {
+                        let filified_path = path.replace("/", "-");
+                        let file_name = "dedupfiles".to_string() + &filified_path;
+                        println!("Saving file list to {}", file_name);
+                        /*
+                                                match bincode::serialize(&entries) {
+                                                    Err(err) => panic!("serialize_digests error {:?}", err),
+                                                    Ok(vec) => return vec,
+                                                }
+                        */
+                    }
+                }
+                None => {
+                    println!("Missing path argument");
+                }
+            }
+        }
+        Some(("compute", args)) => {
+            eprintln!("compute");
+            if args.is_present("streaming") {
+                let path = match args.value_of("path") {
+                    Some(path) => path,
+                    None => {
+                        println!("Missing path argument");
+                        return;
+                    }
+                };
+                let savings = streaming_savings(path, args.is_present("verify"));
+                if args.is_present("json") {
+                    println!("{}", serde_json::to_string_pretty(&savings).unwrap());
+                } else {
+                    print_savings(&savings);
+                }
+                return;
+            }
+            let mut timings = Timings::default();
+            if let Some(mut file_entries) = timed(&mut timings.walk_duration, || input_files(args))
+            {
+                if args.is_present("collapse-hardlinks") {
+                    file_entries = collapse_hardlinks(file_entries);
+                }
+                let file_entries = digest_files_timed(file_entries, &mut timings);
+                if args.is_present("print0") {
+                    let groups = duplicate_groups(&file_entries, args.is_present("verify"));
+                    // Only paths, like find -print0, since xargs -0 would turn
+                    // empty records into empty arguments
+                    if let Err(err) = write_groups(&groups, io::stdout(), b'\0', false) {
+                        eprintln!("Failed to write paths: {}", err);
+                    }
+                    return;
+                }
+                if args.is_present("fdupes") {
+                    let groups = duplicate_groups(&file_entries, args.is_present("verify"));
+                    if let Err(err) = write_fdupes(&groups, io::stdout()) {
+                        eprintln!("Failed to write paths: {}", err);
+                    }
+                    return;
+                }
+
+                let mut savings = compute_savings(&file_entries, args.is_present("verify"));
+                savings.timings = timings;
+                if args.is_present("json") {
+                    println!("{}", serde_json::to_string_pretty(&savings).unwrap());
+                } else {
+                    print_savings(&savings);
+                }
+                //find_candidates(path, 1);
+            }
+        }
+        Some(("dedup", args)) => {
+            let protected = canonical_prefixes(
+                args.values_of("protect")
+                    .map_or(Vec::new(), |paths| paths.map(PathBuf::from).collect()),
+            );
+            let plan = match (args.value_of("apply-plan"), args.value_of("from-fdupes")) {
+                (Some(plan_path), _) => match DedupPlan::load(Path::new(plan_path)) {
+                    Ok(plan) => plan,
+                    Err(err) => {
+                        println!("Failed to load plan {}: {}", plan_path, err);
+                        return;
+                    }
+                },
+                (None, Some(fdupes_path)) => {
+                    let plan = std::fs::File::open(fdupes_path)
+                        .map(io::BufReader::new)
+                        .and_then(read_fdupes);
+                    match plan {
+                        Ok(plan) => plan,
+                        Err(err) => {
+                            println!("Failed to read {}: {}", fdupes_path, err);
+                            return;
+                        }
+                    }
+                }
+                (None, None) => {
+                    // The interactive prompt reads from stdin as well
+                    if args.is_present("from-stdin") && !args.is_present("yes") {
+                        println!("--from-stdin requires --yes");
+                        return;
+                    }
+                    let file_entries = match input_files(args) {
+                        Some(file_entries) => digest_files(file_entries),
+                        None => return,
+                    };
+                    let stdin = io::stdin();
+                    let mut options = DedupOptions {
+                        assume_yes: args.is_present("yes"),
+                        protected: protected.clone(),
+                        verify_contents: args.is_present("verify"),
+                        respect_perms: args.is_present("respect-perms"),
+                        keeper_policy: args
+                            .values_of("prefer")
+                            .map(|paths| prefer_paths(paths.map(PathBuf::from).collect())),
+                    };
+                    match plan_dedup(&file_entries, stdin.lock(), io::stdout(), &mut options) {
+                        Ok(plan) => plan,
+                        Err(err) => {
+                            println!("Dedup failed: {}", err);
+                            return;
+                        }
+                    }
+                }
+            };
+
+            if let Some(plan_path) = args.value_of("save-plan") {
+                match plan.save(Path::new(plan_path)) {
+                    Ok(()) => println!("Saved plan to {}", plan_path),
+                    Err(err) => println!("Failed to save plan {}: {}", plan_path, err),
+                }
+                return;
+            }
+            // Files are only modified on request
+            if !args.is_present("apply") {
+                if let Err(err) = print_plan(&plan, io::stdout()) {
+                    println!("Failed to print plan: {}", err);
+                }
+                println!("Dry run, use --apply to link the files");
+                return;
+            }
+            // A saved plan may be stale, verify the content before linking
+            let verify_digests = args.is_present("apply-plan");
+            match apply_plan(&plan, verify_digests, &protected, io::stdout()) {
+                Ok(bytes) => println!("Reclaimed {}", format_bytes(bytes)),
+                Err(err) => println!("Dedup failed: {}", err),
+            }
+        }
+
+        Some(("inspect", args)) => {
+            let path = args.value_of("digest-file").unwrap();
+            match inspect_digest_file(Path::new(path)) {
+                Ok((header, inventory)) => {
+                    if let Err(err) = write_inventory(&header, &inventory, io::stdout()) {
+                        println!("Failed to write statistics: {}", err);
+                    }
+                }
+                Err(err) => println!("Failed to load {}: {}", path, err),
+            }
+        }
+
+        Some((command, _args)) => {
+            println!("Unknownn command: {:}", command);
+        }
+        None => {
+            println!("Missing command");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use std::sync::Mutex;
+
+    // Records are kept with the name of the thread which logged them, so that
+    // a test can pick out its own records while other tests run
+    static LOG_RECORDS: Mutex<Vec<(Option<String>, String)>> = Mutex::new(Vec::new());
+
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            LOG_RECORDS.lock().unwrap().push((
+                std::thread::current().name().map(String::from),
+                format!("{} {}", record.level(), record.args()),
+            ));
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn entry_with_digest(i: usize, digest: u128) -> FileEntry {
+        FileEntry {
+            name: format!("file{}", i),
+            path: PathBuf::from(format!("/fixture/file{}", i)),
+            len: 4096,
+            digest: AtomicCellU128::new(Some(digest)),
+            inode: None,
+            mode: None,
+        }
+    }
+
+    // A scratch directory for one test, removed again when dropped. The name
+    // is unique per test and process, so concurrent runs never share files.
+    struct Fixture {
+        dir: PathBuf,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Fixture {
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!(
+                "dedup_{}_{}_{}",
+                name,
+                std::process::id(),
+                COUNT.fetch_add(1, AtomicOrdering::Relaxed)
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Fixture { dir }
+        }
+
+        fn path<P: AsRef<Path>>(&self, relative: P) -> PathBuf {
+            self.dir.join(relative)
+        }
+
+        fn root(&self) -> &str {
+            self.dir.to_str().unwrap()
+        }
+
+        // The directory as a path relative to the working directory
+        fn relative_root(&self) -> PathBuf {
+            let up = std::env::current_dir().unwrap().components().count() - 1;
+            (0..up)
+                .map(|_| "..")
+                .collect::<PathBuf>()
+                .join(self.dir.strip_prefix("/").unwrap())
+        }
+
+        // Writes a file, creating its parent directories
+        fn write<P: AsRef<Path>, C: AsRef<[u8]>>(&self, relative: P, contents: C) -> PathBuf {
+            let path = self.path(relative);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, contents).unwrap();
+            path
+        }
+
+        // Scans and digests the tree the way the subcommands do
+        fn scan(&self) -> Vec<FileEntry> {
+            digest_files(filter_files(scan_dir2(self.root())))
+        }
+
+        #[cfg(unix)]
+        fn ino(&self, relative: &str) -> u64 {
+            use std::os::unix::fs::MetadataExt;
+            std::fs::metadata(self.path(relative)).unwrap().ino()
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn assume_yes() -> DedupOptions {
+        DedupOptions {
+            assume_yes: true,
+            ..DedupOptions::default()
+        }
+    }
+
+    #[test]
+    fn digest_is_host_independent() {
+        // First 16 bytes of blake3("hello world"), read as little endian
+        assert_eq!(
+            compute_digest(b"hello world"),
+            0xdb75d085198c8d0b880c0aa7ef8149d7
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_file_names() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let fixture = Fixture::new("non_utf8");
+        let raw_name = OsStr::from_bytes(b"caf\xe9.txt");
+        let path = fixture.write(raw_name, [0u8; 2048]);
+
+        let entries = filter_files(
+            WalkDir::new(&fixture.dir)
+                .into_iter()
+                .filter_map(Result::ok)
+                .filter(|entry| entry.file_type().is_file())
+                .collect(),
+        );
+        assert_eq!(entries.len(), 1);
+        let entry = &entries[0];
+        assert_eq!(entry.path, path);
+        assert_eq!(entry.name, "caf\u{fffd}.txt");
+        assert_eq!(
+            format!("{}", entry.path.display()),
+            format!("{}/caf\u{fffd}.txt", fixture.dir.display())
+        );
+    }
+
+    #[test]
+    fn savings_json() {
+        let fixture = Fixture::new("savings");
+        fixture.write("sub1/dup", [1u8; 2048]);
+        fixture.write("sub2/dup", [1u8; 2048]);
+        fixture.write("sub1/unique", [2u8; 4096]);
+
+        let savings = compute_savings(&fixture.scan(), false);
+        let json = serde_json::to_string(&savings).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(value["file_count"], 3);
+        assert_eq!(value["total_bytes"], 8192);
+        assert_eq!(value["reclaimable_bytes"], 2048);
+        assert_eq!(value["group_count"], 1);
+        let group = &value["top_groups"][0];
+        assert_eq!(group["size"], 2048);
+        assert_eq!(group["paths"].as_array().unwrap().len(), 2);
+        assert_eq!(group["digest"].as_str().unwrap().len(), 32);
+    }
+
+    #[test]
+    fn savings_timings() {
+        let fixture = Fixture::new("timings");
+        fixture.write("sub/a", [1u8; 2048]);
+        fixture.write("sub/b", [1u8; 2048]);
+
+        let mut timings = Timings::default();
+        let file_entries = timed(&mut timings.walk_duration, || {
+            filter_files(scan_dir2(fixture.root()))
+        });
+        let file_entries = digest_files_timed(file_entries, &mut timings);
+        let mut savings = compute_savings(&file_entries, false);
+        savings.timings = timings;
+
+        let timings = &savings.timings;
+        assert!(timings.walk_duration > Duration::from_secs(0));
+        assert!(timings.hash_duration > Duration::from_secs(0));
+        assert!(timings.sort_duration > Duration::from_secs(0));
+
+        let value = serde_json::to_value(&savings).unwrap();
+        let walk_seconds = value["timings"]["walk_duration"].as_f64().unwrap();
+        assert_eq!(walk_seconds, timings.walk_duration.as_secs_f64());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn collapsed_hardlinks_are_not_duplicates() {
+        let fixture = Fixture::new("collapse");
+        for name in &["a", "b", "c"] {
+            fixture.write(Path::new("sub").join(name), [8u8; 2048]);
+        }
+        let scan = |collapse: bool| {
+            let mut file_entries = filter_files(scan_dir2(fixture.root()));
+            if collapse {
+                file_entries = collapse_hardlinks(file_entries);
+            }
+            digest_files(file_entries)
+        };
+
+        let file_entries = scan(true);
+        assert_eq!(
+            compute_savings(&file_entries, false).reclaimable_bytes,
+            2 * 2048
+        );
+        let plan = plan_dedup(&file_entries, io::empty(), io::sink(), &mut assume_yes()).unwrap();
+        apply_plan(&plan, false, &[], io::sink()).unwrap();
+
+        // All three paths are now the same file. Without collapsing, the
+        // paths are still listed, but the file is only counted once.
+        let savings = compute_savings(&scan(true), false);
+        assert_eq!(savings.file_count, 1);
+        assert_eq!(savings.reclaimable_bytes, 0);
+        let savings = compute_savings(&scan(false), false);
+        assert_eq!(savings.file_count, 3);
+        assert_eq!(savings.group_count, 0);
+        assert_eq!(savings.reclaimable_bytes, 0);
+        assert_eq!(savings.deduped_bytes, 2048);
+        assert_eq!(savings.dedup_ratio, 1.0);
+    }
+
+    #[test]
+    fn streaming_savings_matches_full_scan() {
+        let fixture = Fixture::new("streaming");
+        fixture.write("a/one", [1u8; 2048]);
+        fixture.write("a/nested/one", [1u8; 2048]);
+        fixture.write("b/one", [1u8; 2048]);
+        fixture.write("b/same_size", [2u8; 2048]);
+        fixture.write("b/two", [3u8; 8192]);
+        fixture.write("a/two", [3u8; 8192]);
+        fixture.write("a/unique", [4u8; 5000]);
+        fixture.write("a/small", [5u8; 10]);
+        fixture.write("top_level", [1u8; 2048]);
+
+        let full = compute_savings(&fixture.scan(), false);
+        let streamed = streaming_savings(fixture.root(), false);
+
+        assert_eq!(streamed.file_count, 7);
+        assert_eq!(streamed.file_count, full.file_count);
+        assert_eq!(streamed.total_bytes, full.total_bytes);
+        assert_eq!(streamed.reclaimable_bytes, 2 * 2048 + 8192);
+        assert_eq!(streamed.reclaimable_bytes, full.reclaimable_bytes);
+        assert_eq!(streamed.group_count, full.group_count);
+        let sorted_paths = |savings: &Savings| {
+            let mut paths: Vec<Vec<String>> = savings
+                .top_groups
+                .iter()
+                .map(|group| {
+                    let mut paths = group.paths.clone();
+                    paths.sort();
+                    paths
+                })
+                .collect();
+            paths.sort();
+            paths
+        };
+        assert_eq!(sorted_paths(&streamed), sorted_paths(&full));
+    }
+
+    #[test]
+    fn dedup_ratio() {
+        let fixture = Fixture::new("ratio");
+        for name in &["copy1", "copy2", "copy3"] {
+            fixture.write(Path::new("sub").join(name), [1u8; 2048]);
+        }
+        fixture.write("sub/unique", [2u8; 2048]);
+
+        let savings = compute_savings(&fixture.scan(), false);
+        assert_eq!(savings.total_bytes, 8192);
+        assert_eq!(savings.deduped_bytes, 4096);
+        assert!((savings.dedup_ratio - 0.5).abs() < 1e-9);
+
+        let value = serde_json::to_value(&savings).unwrap();
+        assert_eq!(value["deduped_bytes"], 4096);
+        assert_eq!(value["dedup_ratio"], 0.5);
+
+        let empty = compute_savings(&[], false);
+        assert_eq!(empty.dedup_ratio, 1.0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dedup_ratio_counts_hardlinks_once() {
+        let fixture = Fixture::new("ratio_links");
+        let linked = fixture.write("sub/linked", [1u8; 2048]);
+        std::fs::hard_link(&linked, fixture.path("sub/link")).unwrap();
+        fixture.write("sub/copy", [1u8; 2048]);
+        fixture.write("sub/unique", [2u8; 2048]);
+
+        // 4 paths, 3 files on disk, of which one can be linked
+        let savings = compute_savings(&fixture.scan(), false);
+        assert_eq!(savings.total_bytes, 8192);
+        assert_eq!(savings.reclaimable_bytes, 2048);
+        assert_eq!(savings.deduped_bytes, 4096);
+        assert!((savings.dedup_ratio - 4096.0 / 6144.0).abs() < 1e-9);
+        assert_eq!(savings.top_groups[0].paths.len(), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn interactive_dedup() {
+        let fixture = Fixture::new("interactive");
+        for sub in &["a", "b"] {
+            fixture.write(Path::new(sub).join("one"), [1u8; 2048]);
+            fixture.write(Path::new(sub).join("two"), [2u8; 2048]);
+        }
+
+        // First group shown: an invalid answer, then keep the second file.
+        // Second group shown: skip.
+        let input = io::Cursor::new("7\n2\ns\n");
+        let mut output = Vec::new();
+        let mut options = DedupOptions::default();
+        let plan = plan_dedup(&fixture.scan(), input, &mut output, &mut options).unwrap();
+        let reclaimed = apply_plan(&plan, false, &[], io::sink()).unwrap();
+        assert_eq!(reclaimed, 2048);
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .contains("Invalid choice \"7\""));
+
+        let (linked, skipped) = if fixture.ino("a/one") == fixture.ino("b/one") {
+            ("one", "two")
+        } else {
+            ("two", "one")
+        };
+        let ino = |sub: &str, name: &str| fixture.ino(&format!("{}/{}", sub, name));
+        assert_eq!(ino("a", linked), ino("b", linked));
+        assert_ne!(ino("a", skipped), ino("b", skipped));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn second_dedup_run_is_a_no_op() {
+        let fixture = Fixture::new("dedup_twice");
+        for name in &["a", "b", "c"] {
+            fixture.write(Path::new("sub").join(name), [7u8; 4096]);
+        }
+
+        let plan = plan_dedup(&fixture.scan(), io::empty(), io::sink(), &mut assume_yes()).unwrap();
+        assert_eq!(apply_plan(&plan, true, &[], io::sink()).unwrap(), 2 * 4096);
+        assert_eq!(fixture.ino("sub/a"), fixture.ino("sub/c"));
+
+        let second =
+            plan_dedup(&fixture.scan(), io::empty(), io::sink(), &mut assume_yes()).unwrap();
+        assert!(second.groups.is_empty());
+        // Applying the first plan again links nothing either
+        assert_eq!(apply_plan(&plan, true, &[], io::sink()).unwrap(), 0);
+
+        let mut names: Vec<_> = std::fs::read_dir(fixture.path("sub"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn select_keepers_defaults() {
+        let group = [
+            entry_with_digest(2, 1),
+            entry_with_digest(1, 1),
+            entry_with_digest(3, 1),
+        ];
+        let groups = [&group[..], &group[..]];
+
+        let keepers = select_keepers(&groups, io::empty(), io::sink(), &mut assume_yes()).unwrap();
+        assert_eq!(keepers, vec![Some(1), Some(1)]);
+
+        let input = io::Cursor::new("\n");
+        let mut options = DedupOptions::default();
+        let keepers = select_keepers(&groups, input, io::sink(), &mut options).unwrap();
+        assert_eq!(keepers, vec![Some(1), None]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn protected_paths_are_kept() {
+        let fixture = Fixture::new("protected");
+        fixture.write("golden/a", [1u8; 2048]);
+        fixture.write("copies/a", [1u8; 2048]);
+        fixture.write("golden/b", [2u8; 2048]);
+        fixture.write("golden/more/b", [2u8; 2048]);
+        let golden_a = fixture.ino("golden/a");
+
+        let mut options = DedupOptions {
+            assume_yes: false, // protected groups must not prompt
+            protected: canonical_prefixes(vec![fixture.path("golden")]),
+            ..DedupOptions::default()
+        };
+        let plan = plan_dedup(&fixture.scan(), io::empty(), io::sink(), &mut options).unwrap();
+        let reclaimed = apply_plan(&plan, false, &[], io::sink()).unwrap();
+
+        assert_eq!(reclaimed, 2048);
+        assert_eq!(fixture.ino("golden/a"), golden_a);
+        assert_eq!(fixture.ino("copies/a"), golden_a);
+        assert_ne!(fixture.ino("golden/b"), fixture.ino("golden/more/b"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn protected_paths_apply_to_saved_plans() {
+        let fixture = Fixture::new("protected_plan");
+        fixture.write("copies/a", [1u8; 2048]);
+        fixture.write("golden/a", [1u8; 2048]);
+        fixture.write("golden/b", [2u8; 2048]);
+        fixture.write("other/b", [2u8; 2048]);
+        let golden_a = fixture.ino("golden/a");
+        let golden_b = fixture.ino("golden/b");
+
+        // Planned without protection, so the plan replaces golden/a
+        let plan = plan_dedup(&fixture.scan(), io::empty(), io::sink(), &mut assume_yes()).unwrap();
+        let plan_file = fixture.path("plan.json");
+        plan.save(&plan_file).unwrap();
+        let plan = DedupPlan::load(&plan_file).unwrap();
+        assert!(plan.groups.iter().any(|group| group
+            .link
+            .iter()
+            .any(|file| file.path == fixture.path("golden/a"))));
+
+        let protected = canonical_prefixes(vec![fixture.path("golden")]);
+        let mut output = Vec::new();
+        assert_eq!(
+            apply_plan(&plan, true, &protected, &mut output).unwrap(),
+            2048
+        );
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Skipping protected"));
+
+        assert_eq!(fixture.ino("golden/a"), golden_a);
+        assert_ne!(fixture.ino("copies/a"), golden_a);
+        assert_eq!(fixture.ino("golden/b"), golden_b);
+        assert_eq!(fixture.ino("other/b"), golden_b);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn protected_paths_match_any_spelling() {
+        let fixture = Fixture::new("protected_spelling");
+        fixture.write("copies/a", [1u8; 2048]);
+        fixture.write("golden/z", [1u8; 2048]);
+        let golden_z = fixture.ino("golden/z");
+
+        // Scan through a relative root, protect through an absolute path
+        // with a detour
+        let root = fixture.relative_root();
+        let file_entries = digest_files(filter_files(scan_dir2(root.to_str().unwrap())));
+        assert!(file_entries.iter().all(|entry| entry.path.is_relative()));
+
+        let mut options = DedupOptions {
+            assume_yes: true,
+            protected: canonical_prefixes(vec![fixture.path("copies/../golden/")]),
+            ..DedupOptions::default()
+        };
+        let plan = plan_dedup(&file_entries, io::empty(), io::sink(), &mut options).unwrap();
+        assert_eq!(plan.groups.len(), 1);
+        assert!(plan.groups[0].keep.path.ends_with("golden/z"));
+        assert_eq!(apply_plan(&plan, false, &[], io::sink()).unwrap(), 2048);
+
+        assert_eq!(fixture.ino("golden/z"), golden_z);
+        assert_eq!(fixture.ino("copies/a"), golden_z);
+    }
+
+    #[test]
+    fn file_list_from_stdin() {
+        let fixture = Fixture::new("file_list");
+        for name in &["a", "b", "c", "not_listed"] {
+            fixture.write(name, [1u8; 2048]);
+        }
+        fixture.write("small", [1u8; 10]);
+        let listed = ["a", "b", "c", "small", "missing", ""];
+        let paths: Vec<String> = listed
+            .iter()
+            .map(|name| fixture.path(name).to_str().unwrap().to_string())
+            .collect();
+
+        for &separator in b"\n\0" {
+            let input = paths.join(&(separator as char).to_string());
+            let file_entries = read_file_list(io::Cursor::new(input), separator).unwrap();
+            let mut names: Vec<&str> = file_entries.iter().map(|e| e.name.as_str()).collect();
+            names.sort_unstable();
+            assert_eq!(names, vec!["a", "b", "c"]);
+
+            let savings = compute_savings(&digest_files(file_entries), false);
+            assert_eq!(savings.file_count, 3);
+            assert_eq!(savings.reclaimable_bytes, 2 * 2048);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn print0_preserves_newlines() {
+        let fixture = Fixture::new("print0");
+        let tricky = fixture.write("sub/two\nlines", [1u8; 2048]);
+        let plain = fixture.write("sub/plain", [1u8; 2048]);
+        let other = fixture.write("sub/other", [2u8; 2048]);
+        let copy = fixture.write("sub/copy", [2u8; 2048]);
+
+        let mut output = Vec::new();
+        let file_entries = fixture.scan();
+        write_groups(
+            &duplicate_groups(&file_entries, false),
+            &mut output,
+            b'\0',
+            false,
+        )
+        .unwrap();
+
+        // Every record is a path, there are no empty records between groups
+        let output = output.strip_suffix(b"\0").unwrap();
+        let mut records: Vec<PathBuf> = output
+            .split(|&b| b == b'\0')
+            .map(|record| path_from_bytes(record.to_vec()))
+            .collect();
+        records.sort();
+        assert_eq!(records, vec![copy, other, plain, tricky]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn saved_plan_round_trip() {
+        let fixture = Fixture::new("plan");
+        fixture.write("a/file", [5u8; 4096]);
+        fixture.write("b/file", [5u8; 4096]);
+        fixture.write("b/copy", [5u8; 4096]);
+        let plan_path = fixture.path("plan.json");
+
+        let plan = plan_dedup(&fixture.scan(), io::empty(), io::sink(), &mut assume_yes()).unwrap();
+        assert_eq!(plan.groups.len(), 1);
+        assert_eq!(plan.groups[0].keep.path, fixture.path("a/file"));
+        assert_eq!(plan.groups[0].link.len(), 2);
+
+        plan.save(&plan_path).unwrap();
+        let loaded = DedupPlan::load(&plan_path).unwrap();
+        assert_eq!(loaded, plan);
+        assert_eq!(
+            apply_plan(&loaded, true, &[], io::sink()).unwrap(),
+            2 * 4096
+        );
+
+        assert_eq!(fixture.ino("a/file"), fixture.ino("b/file"));
+        assert_eq!(fixture.ino("a/file"), fixture.ino("b/copy"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn saved_plan_keeps_exact_absolute_paths() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let fixture = Fixture::new("plan_paths");
+        let raw = fixture.write(OsStr::from_bytes(b"sub/caf\xe9"), [5u8; 4096]);
+        let plain = fixture.write("sub/plain", [5u8; 4096]);
+        let plan_path = fixture.path("plan.json");
+
+        let root = fixture.relative_root();
+        let file_entries = digest_files(filter_files(scan_dir2(root.to_str().unwrap())));
+        let plan = plan_dedup(&file_entries, io::empty(), io::sink(), &mut assume_yes()).unwrap();
+        assert_eq!(plan.groups[0].keep.path, raw);
+        assert_eq!(plan.groups[0].link[0].path, plain);
+
+        plan.save(&plan_path).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&plan_path).unwrap()).unwrap();
+        let link = &json["groups"][0]["link"][0]["path"];
+        assert_eq!(link.as_str(), plain.to_str());
+        assert!(json["groups"][0]["keep"]["path"].is_array());
+        assert_eq!(DedupPlan::load(&plan_path).unwrap(), plan);
+        assert!(!fixture.path("plan.json.dedup-tmp").exists());
+
+        // A failed save leaves nothing behind
+        let missing_dir = fixture.path("missing/plan.json");
+        assert!(plan.save(&missing_dir).is_err());
+        assert!(!fixture.path("missing").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stale_plan_groups_are_skipped() {
+        let fixture = Fixture::new("stale_plan");
+        fixture.write("a/a1", [1u8; 2048]);
+        fixture.write("a/a2", [1u8; 2048]);
+        fixture.write("b/b1", [2u8; 4096]);
+        fixture.write("b/b2", [2u8; 4096]);
+
+        let plan = plan_dedup(&fixture.scan(), io::empty(), io::sink(), &mut assume_yes()).unwrap();
+        assert_eq!(plan.groups.len(), 2);
+        assert!(plan.validate(true).is_empty());
+
+        // Same size, different content: only detected by the digest check
+        fixture.write("a/a2", [3u8; 2048]);
+        assert!(plan.validate(false).is_empty());
+        assert_eq!(
+            plan.validate(true),
+            vec![PlanConflict::DigestChanged(fixture.path("a/a2"))]
+        );
+
+        let mut output = Vec::new();
+        assert_eq!(apply_plan(&plan, true, &[], &mut output).unwrap(), 4096);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Skipping group"));
+        assert!(output.contains("a/a2 changed content"));
+
+        assert_ne!(fixture.ino("a/a1"), fixture.ino("a/a2"));
+        assert_eq!(fixture.ino("b/b1"), fixture.ino("b/b2"));
+        assert_eq!(std::fs::read(fixture.path("a/a2")).unwrap(), [3u8; 2048]);
+
+        // A removed file is a conflict even without the digest check
+        std::fs::remove_file(fixture.path("a/a1")).unwrap();
+        assert_eq!(
+            plan.validate(false),
+            vec![PlanConflict::Missing(fixture.path("a/a1"))]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn edited_plans_only_link_identical_files() {
+        let fixture = Fixture::new("edited_plan");
+        fixture.write("a/a1", [1u8; 2048]);
+        fixture.write("a/a2", [1u8; 2048]);
+        fixture.write("b/b1", [2u8; 2048]);
+        fixture.write("b/b2", [2u8; 2048]);
+        fixture.write("b/b3", [2u8; 2048]);
+        let plan_file = fixture.path("plan.json");
+        plan_dedup(&fixture.scan(), io::empty(), io::sink(), &mut assume_yes())
+            .unwrap()
+            .save(&plan_file)
+            .unwrap();
+
+        // Move a file into the group of another content, as an edit could
+        let mut plan = DedupPlan::load(&plan_file).unwrap();
+        assert_eq!(plan.groups.len(), 2);
+        plan.groups.sort_by(|a, b| a.keep.path.cmp(&b.keep.path));
+        let moved = plan.groups[1].link.pop().unwrap();
+        assert_eq!(moved.path, fixture.path("b/b3"));
+        plan.groups[0].link.push(moved);
+        plan.save(&plan_file).unwrap();
+
+        let plan = DedupPlan::load(&plan_file).unwrap();
+        assert_eq!(
+            plan.validate(false),
+            vec![PlanConflict::Mismatched {
+                path: fixture.path("b/b3"),
+                keep: fixture.path("a/a1"),
+            }]
+        );
+
+        let mut output = Vec::new();
+        assert_eq!(apply_plan(&plan, false, &[], &mut output).unwrap(), 2048);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("b/b3 doesn't match"));
+
+        assert_ne!(fixture.ino("a/a1"), fixture.ino("a/a2"));
+        assert_ne!(fixture.ino("a/a1"), fixture.ino("b/b3"));
+        assert_eq!(fixture.ino("b/b1"), fixture.ino("b/b2"));
+        assert_eq!(std::fs::read(fixture.path("b/b3")).unwrap(), [2u8; 2048]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unreadable_plan_files_are_conflicts() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let fixture = Fixture::new("unreadable_plan");
+        fixture.write("a/a1", [1u8; 2048]);
+        let locked = fixture.write("a/a2", [1u8; 2048]);
+        fixture.write("b/b1", [2u8; 4096]);
+        fixture.write("b/b2", [2u8; 4096]);
+        let plan = plan_dedup(&fixture.scan(), io::empty(), io::sink(), &mut assume_yes()).unwrap();
+        assert_eq!(plan.groups.len(), 2);
+
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+        // Permissions don't apply to root
+        if std::fs::File::open(&locked).is_ok() {
+            eprintln!("skipping: file permissions are not enforced");
+            return;
+        }
+        assert!(plan.validate(false).is_empty());
+        assert_eq!(
+            plan.validate(true),
+            vec![PlanConflict::Unreadable(
+                locked.clone(),
+                io::ErrorKind::PermissionDenied
+            )]
+        );
+
+        let mut output = Vec::new();
+        assert_eq!(apply_plan(&plan, true, &[], &mut output).unwrap(), 4096);
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .contains("a/a2 can't be read"));
+        assert_ne!(fixture.ino("a/a1"), fixture.ino("a/a2"));
+        assert_eq!(fixture.ino("b/b1"), fixture.ino("b/b2"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unreadable_files_are_not_hashed() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let fixture = Fixture::new("unreadable_scan");
+        fixture.write("a/a1", [1u8; 2048]);
+        fixture.write("a/a2", [1u8; 2048]);
+        let locked = fixture.write("a/a3", [1u8; 2048]);
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+        // Permissions don't apply to root
+        if std::fs::File::open(&locked).is_ok() {
+            eprintln!("skipping: file permissions are not enforced");
+            return;
+        }
+
+        let file_entries = fixture.scan();
+        assert_eq!(file_entries.len(), 2);
+        assert!(file_entries.iter().all(|entry| entry.path != locked));
+
+        let plan = plan_dedup(&file_entries, io::empty(), io::sink(), &mut assume_yes()).unwrap();
+        assert_eq!(plan.groups.len(), 1);
+        assert_eq!(plan.groups[0].keep.path, fixture.path("a/a1"));
+        assert_eq!(plan.groups[0].link.len(), 1);
+        assert_eq!(plan.groups[0].link[0].path, fixture.path("a/a2"));
+    }
+
+    #[test]
+    fn verified_groups_need_identical_contents() {
+        let fixture = Fixture::new("verify");
+        let contents: [&[u8]; 4] = [&[1; 2048], &[1; 2048], &[2; 2048], &[1; 1024]];
+        let file_entries: Vec<FileEntry> = contents
+            .iter()
+            .enumerate()
+            .map(|(i, content)| {
+                let entry = FileEntry::from_path(fixture.write(i.to_string(), content)).unwrap();
+                // Force a digest collision
+                entry.digest.store(Some(42));
+                entry
+            })
+            .collect();
+
+        let trusting = duplicate_groups(&file_entries, false);
+        assert_eq!(trusting.len(), 1);
+        assert_eq!(trusting[0].len(), 4);
+
+        let verified = duplicate_groups(&file_entries, true);
+        assert_eq!(verified.len(), 1);
+        let paths: Vec<&Path> = verified[0].iter().map(|e| e.path.as_path()).collect();
+        assert_eq!(paths, vec![fixture.path("0"), fixture.path("1")]);
+        assert_eq!(compute_savings(&file_entries, true).reclaimable_bytes, 2048);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unreadable_directories_are_recorded() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let fixture = Fixture::new("unreadable");
+        let locked = fixture.path("sub/locked");
+        fixture.write("sub/locked/hidden", [1u8; 2048]);
+        fixture.write("sub/open/visible", [1u8; 2048]);
+        fixture.write("sub/sibling", [1u8; 2048]);
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+
+        // Permissions don't apply to root
+        if std::fs::read_dir(&locked).is_ok() {
+            eprintln!("skipping: directory permissions are not enforced");
+            return;
+        }
+
+        let (entries, errors) = scan_dir_with_errors(fixture.root());
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut names: Vec<String> = filter_files(entries)
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        names.sort_unstable();
+        assert_eq!(names, vec!["sibling", "visible"]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path(), Some(locked.as_path()));
+    }
+
+    #[test]
+    fn scan_progress_totals_workers() {
+        let fixture = Fixture::new("progress");
+        for (i, count) in [3, 0, 7].iter().enumerate() {
+            std::fs::create_dir_all(fixture.path(i.to_string())).unwrap();
+            for j in 0..*count {
+                fixture.write(format!("{}/{}", i, j), [0u8; 1]);
+            }
+        }
+
+        let progress = ScanProgress::new(ProgressBar::hidden(), 3);
+        let lengths: Vec<usize> = (0..3)
+            .into_par_iter()
+            .map(|i| {
+                let path = fixture.path(i.to_string());
+                let mut errors = Vec::new();
+                let entries = scan_subdir(
+                    path.to_str().unwrap(),
+                    &ProgressBar::hidden(),
+                    &progress,
+                    &mut errors,
+                );
+                progress.worker_done();
+                entries.len()
+            })
+            .collect();
+
+        // Each walk includes the subdirectory itself
+        assert_eq!(lengths, vec![4, 1, 8]);
+        assert_eq!(progress.discovered(), lengths.iter().sum::<usize>());
+        assert!(progress.bar.is_finished());
+    }
+
+    #[test]
+    fn scan_matches_serial_walk() {
+        let fixture = Fixture::new("scan");
+        for i in 0..8 {
+            std::fs::create_dir_all(fixture.path(format!("{}/nested", i))).unwrap();
+            for j in 0..i * 3 {
+                fixture.write(format!("{}/nested/file{}", i, j), [0u8; 1]);
+                fixture.write(format!("{}/same{}", i, j), [0u8; 1]);
+            }
+        }
+
+        let (entries, errors) = scan_dir_with_errors(fixture.root());
+        assert!(errors.is_empty());
+        // Entries are sorted by name only, compare as sets of paths
+        let mut scanned: Vec<PathBuf> = entries.iter().map(|entry| entry.path()).collect();
+        assert!(entries
+            .windows(2)
+            .all(|pair| pair[0].file_name() <= pair[1].file_name()));
+
+        let mut serial: Vec<PathBuf> = WalkDir::new(&fixture.dir)
+            .parallelism(jwalk::Parallelism::Serial)
+            .into_iter()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| *path != fixture.dir)
+            .collect();
+        scanned.sort();
+        serial.sort();
+        assert_eq!(scanned, serial);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dry_run_leaves_files_unchanged() {
+        use std::os::unix::fs::MetadataExt;
+
+        let fixture = Fixture::new("dry_run");
+        fixture.write("sub/a", [4u8; 4096]);
+        fixture.write("sub/b", [4u8; 4096]);
+        fixture.write("sub/c", [5u8; 4096]);
+        let snapshot = || {
+            let mut files: Vec<(PathBuf, u64, Vec<u8>)> = std::fs::read_dir(fixture.path("sub"))
+                .unwrap()
+                .map(|entry| {
+                    let path = entry.unwrap().path();
+                    let ino = std::fs::metadata(&path).unwrap().ino();
+                    let content = std::fs::read(&path).unwrap();
+                    (path, ino, content)
+                })
+                .collect();
+            files.sort();
+            files
+        };
+        let before = snapshot();
+
+        let plan = plan_dedup(&fixture.scan(), io::empty(), io::sink(), &mut assume_yes()).unwrap();
+        let mut output = Vec::new();
+        assert_eq!(print_plan(&plan, &mut output).unwrap(), 4096);
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains(&format!("Keep {}", fixture.path("sub/a").display())));
+        assert!(output.contains(&format!("  link {}", fixture.path("sub/b").display())));
+        assert!(output.contains("Would reclaim 4.0 KiB"));
+        assert_eq!(snapshot(), before);
+    }
+
+    #[test]
+    fn fdupes_round_trip() {
+        let fixture = Fixture::new("fdupes");
+        for name in &["a1", "a2", "a3"] {
+            fixture.write(Path::new("sub").join(name), [1u8; 2048]);
+        }
+        for name in &["b1", "b2"] {
+            fixture.write(Path::new("sub").join(name), [2u8; 4096]);
+        }
+        fixture.write("sub/unique", [3u8; 2048]);
+
+        let file_entries = fixture.scan();
+        let groups = duplicate_groups(&file_entries, false);
+        let mut output = Vec::new();
+        write_fdupes(&groups, &mut output).unwrap();
+        let text = String::from_utf8(output.clone()).unwrap();
+        assert_eq!(text.matches("\n\n").count(), 2);
+
+        let plan = read_fdupes(io::Cursor::new(output)).unwrap();
+        let planned: Vec<Vec<&Path>> = plan
+            .groups
+            .iter()
+            .map(|group| {
+                std::iter::once(&group.keep)
+                    .chain(&group.link)
+                    .map(|file| file.path.as_path())
+                    .collect()
+            })
+            .collect();
+        let expected: Vec<Vec<&Path>> = groups
+            .iter()
+            .map(|group| group.iter().map(|entry| entry.path.as_path()).collect())
+            .collect();
+        assert_eq!(planned, expected);
+        assert_eq!(plan.reclaimable_bytes(), 2 * 2048 + 4096);
+
+        // Files which differ are never planned for linking
+        let mixed = format!(
+            "{}\n{}\n",
+            fixture.path("sub/a1").display(),
+            fixture.path("sub/unique").display()
+        );
+        let err = read_fdupes(io::Cursor::new(mixed)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fdupes_rejects_unusable_paths() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let fixture = Fixture::new("fdupes_unusable");
+        let file = fixture.write("sub/file", [1u8; 2048]);
+        let dangling = fixture.path("sub/dangling");
+        std::os::unix::fs::symlink(fixture.path("sub/gone"), &dangling).unwrap();
+        let listed = |other: &Path| format!("{}\n{}\n", file.display(), other.display());
+
+        let err = read_fdupes(io::Cursor::new(listed(&dangling))).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let missing = fixture.path("sub/missing");
+        let err = read_fdupes(io::Cursor::new(listed(&missing))).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("sub/missing"));
+
+        let locked = fixture.write("sub/locked", [1u8; 2048]);
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+        // Permissions don't apply to root
+        if std::fs::File::open(&locked).is_err() {
+            let err = read_fdupes(io::Cursor::new(listed(&locked))).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn phases_are_logged() {
+        let _ = log::set_logger(&CaptureLogger);
+        log::set_max_level(log::LevelFilter::Info);
+
+        let fixture = Fixture::new("logging");
+        fixture.write("sub/a", [1u8; 2048]);
+        fixture.write("sub/b", [1u8; 2048]);
+        let plan = plan_dedup(&fixture.scan(), io::empty(), io::sink(), &mut assume_yes()).unwrap();
+        apply_plan(&plan, false, &[], io::sink()).unwrap();
+
+        let thread = std::thread::current().name().map(String::from);
+        let records = LOG_RECORDS.lock().unwrap();
+        let find = |prefix: &str| -> Vec<&String> {
+            records
+                .iter()
+                .filter(|(name, record)| *name == thread && record.starts_with(prefix))
+                .map(|(_, record)| record)
+                .collect()
+        };
+        let walk = find("INFO phase=walk");
+        assert_eq!(walk.len(), 1);
+        assert!(walk[0].contains(&format!("root={:?}", fixture.root())));
+        assert!(walk[0].contains("entries=3 "));
+        let hash = find("INFO phase=hash");
+        assert_eq!(hash.len(), 1);
+        assert!(hash[0].contains("entries=2 skipped=0 "));
+        let sort = find("INFO phase=sort");
+        assert_eq!(sort.len(), 1);
+        assert!(sort[0].contains("entries=2 "));
+        let dedup = find("INFO phase=dedup");
+        assert_eq!(dedup.len(), 1);
+        assert!(dedup[0].contains("entries=1 conflicts=0 bytes=2048 "));
+        for record in walk.iter().chain(&hash).chain(&sort).chain(&dedup) {
+            assert!(record.contains(" elapsed="));
+        }
+    }
+
+    #[test]
+    fn digest_progress_counts_bytes() {
+        let fixture = Fixture::new("digest_progress");
+        let file_entries: Vec<FileEntry> = [10usize, 0, 3000, 70000]
+            .iter()
+            .enumerate()
+            .map(|(i, &size)| {
+                FileEntry::from_path(fixture.write(i.to_string(), vec![1u8; size])).unwrap()
+            })
+            .collect();
+
+        let pb = digest_progress_bar(&file_entries);
+        pb.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        assert_eq!(pb.length(), 73010);
+        assert_eq!(pb.position(), 0);
+
+        assert!(compute_digests_with_progress(&file_entries, &pb).is_empty());
+        assert_eq!(pb.position(), pb.length());
+        assert!(pb.is_finished());
+        assert!(file_entries
+            .iter()
+            .all(|entry| entry.digest.load().is_some()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeper_policy_overrides_default() {
+        let fixture = Fixture::new("keeper_policy");
+        fixture.write("a/file", [6u8; 2048]);
+        fixture.write("a/deeper/still/file", [6u8; 2048]);
+        fixture.write("b/file", [6u8; 2048]);
+        let longest = fixture.ino("a/deeper/still/file");
+
+        let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+        let policy_calls = calls.clone();
+        let mut options = DedupOptions {
+            assume_yes: true,
+            keeper_policy: Some(Box::new(move |group: &[FileEntry]| {
+                policy_calls.set(policy_calls.get() + 1);
+                (0..group.len())
+                    .max_by_key(|&i| group[i].path.as_os_str().len())
+                    .unwrap()
+            })),
+            ..DedupOptions::default()
+        };
+        let plan = plan_dedup(&fixture.scan(), io::empty(), io::sink(), &mut options).unwrap();
+        assert_eq!(calls.get(), 1);
+        assert_eq!(
+            plan.groups[0].keep.path,
+            fixture.path("a/deeper/still/file")
+        );
+        assert_eq!(apply_plan(&plan, false, &[], io::sink()).unwrap(), 2 * 2048);
+
+        assert_eq!(fixture.ino("a/deeper/still/file"), longest);
+        assert_eq!(fixture.ino("a/file"), longest);
+        assert_eq!(fixture.ino("b/file"), longest);
+
+        // A policy which picks no file of the group is an error
+        let mut options = DedupOptions {
+            assume_yes: true,
+            keeper_policy: Some(Box::new(|group: &[FileEntry]| group.len())),
+            ..DedupOptions::default()
+        };
+        fixture.write("c/one", [7u8; 2048]);
+        fixture.write("c/two", [7u8; 2048]);
+        let err = plan_dedup(&fixture.scan(), io::empty(), io::sink(), &mut options).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn preferred_paths_are_kept() {
+        let fixture = Fixture::new("prefer");
+        fixture.write("backup/a", [1u8; 2048]);
+        fixture.write("main/a", [1u8; 2048]);
+        fixture.write("backup/b", [2u8; 2048]);
+        fixture.write("other/b", [2u8; 2048]);
+
+        let mut options = DedupOptions {
+            assume_yes: true,
+            keeper_policy: Some(prefer_paths(vec![fixture.path("main/../main")])),
+            ..DedupOptions::default()
+        };
+        let plan = plan_dedup(&fixture.scan(), io::empty(), io::sink(), &mut options).unwrap();
+        let mut keepers: Vec<&Path> = plan.groups.iter().map(|g| g.keep.path.as_path()).collect();
+        keepers.sort();
+        // The group without a preferred file keeps the first path
+        assert_eq!(
+            keepers,
+            vec![fixture.path("backup/b"), fixture.path("main/a")]
+        );
+    }
+
+    #[test]
+    fn inspect_saved_digest_file() {
+        use structureddedup::prelude::FsIngester;
+
+        let fixture = Fixture::new("inspect");
+        fixture.write("tree/a.log", [1u8; 3000]);
+        fixture.write("tree/sub/b.log", [2u8; 3000]);
+        fixture.write("tree/sub/c", [3u8; 100]);
+        let digest_file = fixture.path("digest");
+        let table = FsIngester::new(&fixture.path("tree")).ingest();
+        persistence::save(&digest_file, &table).unwrap();
+
+        let (header, inventory) = inspect_digest_file(&digest_file).unwrap();
+        assert_eq!(header.version, persistence::FORMAT_VERSION);
+        assert_eq!(inventory.file_count, table.iter_files().count());
+        assert_eq!(inventory.total_bytes, 6100);
+
+        let mut output = Vec::new();
+        write_inventory(&header, &inventory, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("format: version 1, blake3 digests\n"));
+        assert!(output.contains(&format!("files : {}\n", inventory.file_count)));
+        assert!(output.contains("2 files of 2.9 KiB"));
+        assert!(output.contains("   log: 2 files, 5.9 KiB\n"));
+        assert!(output.contains("   (none): 1 files, 100 B\n"));
+
+        assert!(inspect_digest_file(&fixture.path("does_not_exist")).is_err());
+    }
+
+    #[test]
+    fn scan_order_is_deterministic() {
+        let fixture = Fixture::new("deterministic");
+        for i in 0..16 {
+            // Same names and contents in every directory
+            fixture.write(format!("dir{}/config", i), [1u8; 2048]);
+            fixture.write(format!("dir{}/data", i), vec![i as u8; 4096]);
+        }
+
+        let scan = || {
+            let entries = scan_dir2(fixture.root());
+            let paths: Vec<PathBuf> = entries.iter().map(|entry| entry.path()).collect();
+            let file_entries = digest_files(filter_files(entries));
+            (paths, serde_json::to_vec(&file_entries).unwrap())
+        };
+        let (first_paths, first_entries) = scan();
+        let keys: Vec<_> = first_paths
+            .iter()
+            .map(|path| (path.file_name(), path.parent()))
+            .collect();
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        for _ in 0..4 {
+            let (paths, entries) = scan();
+            assert_eq!(paths, first_paths);
+            assert_eq!(entries, first_entries);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn special_files_are_skipped() {
+        let fixture = Fixture::new("special");
+        let file = fixture.write("sub/file", [1u8; 2048]);
+        let fifo = fixture.path("sub/fifo");
+        let status = std::process::Command::new("mkfifo")
+            .arg(&fifo)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        assert!(FileEntry::from_path(fifo.clone()).is_none());
+        assert!(FileEntry::from_path(PathBuf::from("/dev/null")).is_none());
+        let scanned: Vec<PathBuf> = scan_dir2(fixture.root())
+            .iter()
+            .filter_map(FileEntry::from_jwalk_entry)
+            .map(|entry| entry.path)
+            .collect();
+        assert_eq!(scanned, vec![file.clone()]);
+
+        // Opening the fifo for reading would block
+        let listed = format!("{}\n{}\n", file.display(), fifo.display());
+        let err = read_fdupes(io::Cursor::new(listed)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn respect_perms_keeps_modes_apart() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let fixture = Fixture::new("perms");
+        for (name, mode) in &[("a", 0o644), ("b", 0o600), ("c", 0o644)] {
+            let path = fixture.write(Path::new("sub").join(name), [9u8; 2048]);
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(*mode)).unwrap();
+        }
+        let file_entries = fixture.scan();
+
+        // Without the flag, modes don't affect the order of the files
+        let groups = duplicate_groups(&file_entries, false);
+        let paths: Vec<_> = groups[0].iter().map(|entry| entry.path.clone()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                fixture.path("sub/a"),
+                fixture.path("sub/b"),
+                fixture.path("sub/c")
+            ]
+        );
+        let plan = plan_dedup(&file_entries, io::empty(), io::sink(), &mut assume_yes()).unwrap();
+        assert_eq!(plan.reclaimable_bytes(), 2 * 2048);
+
+        let mut options = DedupOptions {
+            assume_yes: true,
+            respect_perms: true,
+            ..DedupOptions::default()
+        };
+        let plan = plan_dedup(&file_entries, io::empty(), io::sink(), &mut options).unwrap();
+        assert_eq!(plan.groups.len(), 1);
+        assert_eq!(plan.groups[0].keep.path, fixture.path("sub/a"));
+        assert_eq!(plan.groups[0].link.len(), 1);
+        assert_eq!(plan.groups[0].link[0].path, fixture.path("sub/c"));
+        apply_plan(&plan, false, &[], io::sink()).unwrap();
+
+        assert_eq!(fixture.ino("sub/a"), fixture.ino("sub/c"));
+        assert_ne!(fixture.ino("sub/a"), fixture.ino("sub/b"));
+        let mode = std::fs::metadata(fixture.path("sub/b"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o7777, 0o600);
+    }
+
+    #[test]
+    fn parallel_grouping_matches_serial() {
+        let mut rng = rand::thread_rng();
+        let mut entries: Vec<FileEntry> = (0..100_000)
+            .map(|i| entry_with_digest(i, rng.gen_range(0, 5000)))
+            .collect();
+        entries.par_sort_unstable_by(|a, b| a.digest.load().cmp(&b.digest.load()));
+
+        let serial: Vec<&[FileEntry]> = group_by_digest(&entries).collect();
+        let parallel = par_group_by_digest(&entries);
+
+        assert_eq!(serial.len(), parallel.len());
+        for (s, p) in serial.iter().zip(parallel.iter()) {
+            assert_eq!(s.as_ptr(), p.as_ptr());
+            assert_eq!(s.len(), p.len());
         }
+        assert!(par_group_by_digest(&[]).is_empty());
     }
 }
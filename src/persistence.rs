@@ -0,0 +1,484 @@
+//! Loading and saving of file system tables ("digest files").
+
+use bincode::Options;
+use filesystemtable::FsTable;
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum EntriesFileError {
+    #[error("file io error for {path:?}")]
+    FileIo {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("corrupt or truncated file")]
+    Corrupt(#[source] io::Error),
+    #[error("data format error")]
+    DataFormat(#[from] Box<bincode::ErrorKind>),
+    #[error("unsupported digest file version {0}")]
+    UnsupportedVersion(u32),
+    #[error("unknown digest algorithm {0}")]
+    UnknownAlgorithm(u32),
+}
+
+/// The digest algorithm of the table entries in a digest file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestAlgorithm {
+    Blake3,
+}
+
+impl DigestAlgorithm {
+    fn id(self) -> u32 {
+        match self {
+            DigestAlgorithm::Blake3 => 1,
+        }
+    }
+
+    fn from_id(id: u32) -> Option<DigestAlgorithm> {
+        match id {
+            1 => Some(DigestAlgorithm::Blake3),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DigestAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DigestAlgorithm::Blake3 => write!(f, "blake3"),
+        }
+    }
+}
+
+/// Format information from the header of a digest file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub version: u32,
+    pub algorithm: DigestAlgorithm,
+}
+
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "version {}, {} digests", self.version, self.algorithm)
+    }
+}
+
+/// The digest file format version written by [`save`]. Files with other
+/// versions are not loaded.
+pub const FORMAT_VERSION: u32 = 1;
+
+// A digest file starts with a header: a magic number, the format version and
+// the digest algorithm id, then a checksum of the serialized table and the
+// length of the serialized table. Numbers are little endian.
+const MAGIC: [u8; 4] = *b"SDDF";
+const CHECKSUM_OFFSET: usize = MAGIC.len() + 4 + 4;
+const CHECKSUM_LEN: usize = 16;
+const HEADER_LEN: usize = CHECKSUM_OFFSET + CHECKSUM_LEN + 8;
+
+fn encode_header(checksum: u128, serialized_len: u64) -> [u8; HEADER_LEN] {
+    let mut header = [0u8; HEADER_LEN];
+    header[..4].copy_from_slice(&MAGIC);
+    header[4..8].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header[8..12].copy_from_slice(&DigestAlgorithm::Blake3.id().to_le_bytes());
+    header[CHECKSUM_OFFSET..CHECKSUM_OFFSET + CHECKSUM_LEN]
+        .copy_from_slice(&checksum.to_le_bytes());
+    header[CHECKSUM_OFFSET + CHECKSUM_LEN..].copy_from_slice(&serialized_len.to_le_bytes());
+    header
+}
+
+// Returns the header, the checksum and the serialized length
+fn decode_header(header: &[u8; HEADER_LEN]) -> Result<(Header, u128, u64), EntriesFileError> {
+    let u32_at = |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap());
+    if header[..4] != MAGIC {
+        return Err(EntriesFileError::Corrupt(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a digest file",
+        )));
+    }
+    let version = u32_at(4);
+    if version != FORMAT_VERSION {
+        return Err(EntriesFileError::UnsupportedVersion(version));
+    }
+    let algorithm = DigestAlgorithm::from_id(u32_at(8))
+        .ok_or_else(|| EntriesFileError::UnknownAlgorithm(u32_at(8)))?;
+    let checksum = u128::from_le_bytes(
+        header[CHECKSUM_OFFSET..CHECKSUM_OFFSET + CHECKSUM_LEN]
+            .try_into()
+            .unwrap(),
+    );
+    let serialized_len =
+        u64::from_le_bytes(header[CHECKSUM_OFFSET + CHECKSUM_LEN..].try_into().unwrap());
+    Ok((Header { version, algorithm }, checksum, serialized_len))
+}
+
+// Upper bound on how well zstd compresses anything: a 4 byte RLE block (block
+// header and the repeated byte) decodes to at most 128 KiB. The serialized
+// length in the header must fit the compressed size, so that a corrupt header
+// can't lift the decoding limit beyond what the file could possibly hold.
+const MAX_COMPRESSION_RATIO: u64 = 128 * 1024 / 4;
+
+// Same encoding as bincode::serialize(), but with a byte limit so that
+// corrupt length prefixes fail instead of triggering huge allocations.
+fn bincode_options(limit: u64) -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit)
+}
+
+// Wraps a reader or writer and hashes the data passing through it
+struct Hashing<T> {
+    inner: T,
+    hasher: blake3::Hasher,
+    // Bytes passed through so far
+    len: u64,
+}
+
+impl<T> Hashing<T> {
+    fn new(inner: T) -> Hashing<T> {
+        Hashing {
+            inner,
+            hasher: blake3::Hasher::new(),
+            len: 0,
+        }
+    }
+
+    fn checksum(&self) -> u128 {
+        let mut buffer = [0u8; CHECKSUM_LEN];
+        self.hasher.finalize_xof().fill(&mut buffer);
+        u128::from_le_bytes(buffer)
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher.update(&buf[..len]);
+        self.len += len as u64;
+        Ok(len)
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.hasher.update(&buf[..len]);
+        self.len += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Returns a checksum of the table's serialized form. Equal tables have
+/// equal checksums; this is also the checksum stored in digest files.
+pub fn checksum(table: &FsTable) -> bincode::Result<u128> {
+    let mut writer = Hashing::new(io::sink());
+    bincode::serialize_into(&mut writer, table)?;
+    Ok(writer.checksum())
+}
+
+/// Loads a table previously written by [`save`].
+pub fn load<P: AsRef<Path>>(path: P) -> Result<FsTable, EntriesFileError> {
+    load_with_header(path).map(|(_, table)| table)
+}
+
+/// Like [`load`], but also returns the format information from the header.
+pub fn load_with_header<P: AsRef<Path>>(path: P) -> Result<(Header, FsTable), EntriesFileError> {
+    let path = path.as_ref();
+    let file_io = |source: io::Error| EntriesFileError::FileIo {
+        path: path.into(),
+        source,
+    };
+    let corrupt = |message: &str| {
+        EntriesFileError::Corrupt(io::Error::new(io::ErrorKind::InvalidData, message))
+    };
+    let mut file = File::open(path).map_err(file_io)?;
+    let file_len = file.metadata().map_err(file_io)?.len();
+    let mut header = [0u8; HEADER_LEN];
+    file.read_exact(&mut header)
+        .map_err(EntriesFileError::Corrupt)?;
+    let (header, checksum, serialized_len) = decode_header(&header)?;
+    if serialized_len > file_len.saturating_mul(MAX_COMPRESSION_RATIO) {
+        return Err(corrupt("serialized length out of range"));
+    }
+
+    let decoder = zstd::stream::read::Decoder::new(file).map_err(file_io)?;
+    let mut reader = Hashing::new(decoder);
+    let table = bincode_options(serialized_len)
+        .deserialize_from(&mut reader)
+        .map_err(|err| match *err {
+            // Read errors here come from the decompressor, which fails on
+            // damaged or truncated frames.
+            bincode::ErrorKind::Io(source) => EntriesFileError::Corrupt(source),
+            other => EntriesFileError::DataFormat(Box::new(other)),
+        })?;
+
+    // Trailing data is part of the checksum too
+    io::copy(&mut reader, &mut io::sink()).map_err(EntriesFileError::Corrupt)?;
+    if reader.len != serialized_len {
+        return Err(corrupt("length mismatch"));
+    }
+    if reader.checksum() != checksum {
+        return Err(corrupt("checksum mismatch"));
+    }
+    Ok((header, table))
+}
+
+/// Writes a table to a digest file: a header with the format version, the
+/// digest algorithm, a checksum and the length of the serialized table,
+/// followed by the zstd-compressed bincode encoding of the table.
+///
+/// The file is written under a temporary name and renamed into place, so that
+/// a failed save leaves an existing digest file intact.
+pub fn save<P: AsRef<Path>>(path: P, table: &FsTable) -> Result<(), EntriesFileError> {
+    let path = path.as_ref();
+    let mut temp_path = path.as_os_str().to_os_string();
+    temp_path.push(".dedup-tmp");
+    let temp_path = PathBuf::from(temp_path);
+
+    let result = write(&temp_path, table).and_then(|()| {
+        std::fs::rename(&temp_path, path).map_err(|source| EntriesFileError::FileIo {
+            path: path.into(),
+            source,
+        })
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+fn write(path: &Path, table: &FsTable) -> Result<(), EntriesFileError> {
+    let file_io = |source: io::Error| EntriesFileError::FileIo {
+        path: path.into(),
+        source,
+    };
+    let mut writer = BufWriter::new(File::create(path).map_err(file_io)?);
+    // Reserve room for the header, which is known once the table is written
+    writer.write_all(&[0u8; HEADER_LEN]).map_err(file_io)?;
+
+    let encoder = zstd::stream::write::Encoder::new(writer, 0).map_err(file_io)?;
+    let mut hashing_encoder = Hashing::new(encoder);
+    bincode::serialize_into(&mut hashing_encoder, table).map_err(|err| match *err {
+        bincode::ErrorKind::Io(source) => file_io(source),
+        other => EntriesFileError::DataFormat(Box::new(other)),
+    })?;
+
+    let checksum = hashing_encoder.checksum();
+    let serialized_len = hashing_encoder.len;
+    let mut writer = hashing_encoder.inner.finish().map_err(file_io)?;
+    writer
+        .seek(SeekFrom::Start(0))
+        .and_then(|_| writer.write_all(&encode_header(checksum, serialized_len)))
+        .and_then(|_| writer.flush())
+        .map_err(file_io)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Fixture;
+    use filesystemtable::FsIngester;
+
+    #[test]
+    fn save_then_load() {
+        let cwd = std::env::current_dir().unwrap();
+        let fixture = Fixture::new("digest_save_load");
+        let path = &fixture.path("digest");
+        let table = FsIngester::new(&cwd).ingest();
+        save(path, &table).unwrap();
+        let loaded = load(path).unwrap();
+
+        assert_eq!(loaded.len(), table.len());
+        assert_eq!(
+            bincode::serialize(&loaded).unwrap(),
+            bincode::serialize(&table).unwrap()
+        );
+    }
+
+    #[test]
+    fn failed_save_keeps_existing_file() {
+        let cwd = std::env::current_dir().unwrap();
+        let fixture = Fixture::new("digest_failed_save");
+        let path = &fixture.path("digest");
+        let temp_path = &fixture.path("digest.dedup-tmp");
+        let table = FsIngester::new(&cwd).ingest();
+        save(path, &table).unwrap();
+        assert!(!temp_path.exists());
+        let bytes = std::fs::read(path).unwrap();
+
+        // The temporary file can't be created where a directory is
+        std::fs::create_dir(temp_path).unwrap();
+        let result = save(path, &table);
+        std::fs::remove_dir(temp_path).unwrap();
+        match result {
+            Err(EntriesFileError::FileIo { path, .. }) => assert_eq!(&path, temp_path),
+            _ => panic!("expected FileIo error"),
+        }
+        assert_eq!(std::fs::read(path).unwrap(), bytes);
+    }
+
+    #[test]
+    fn load_error_variants() {
+        let cwd = std::env::current_dir().unwrap();
+        let fixture = Fixture::new("digest_truncated");
+        let path = &fixture.path("digest");
+        save(path, &FsIngester::new(&cwd).ingest()).unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        std::fs::write(path, &bytes[..bytes.len() / 2]).unwrap();
+        assert!(matches!(
+            load(path),
+            Err(EntriesFileError::Corrupt(_)) | Err(EntriesFileError::DataFormat(_))
+        ));
+
+        let missing = &fixture.path("does_not_exist");
+        match load(missing) {
+            Err(EntriesFileError::FileIo { path, source }) => {
+                assert_eq!(&path, missing);
+                assert_eq!(source.kind(), io::ErrorKind::NotFound);
+            }
+            _ => panic!("expected FileIo error"),
+        }
+    }
+
+    #[test]
+    fn checksum_detects_flipped_bytes() {
+        let cwd = std::env::current_dir().unwrap();
+        let fixture = Fixture::new("digest_checksum");
+        let path = &fixture.path("digest");
+        let table = FsIngester::new(&cwd).ingest();
+        save(path, &table).unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        assert_eq!(
+            bytes[CHECKSUM_OFFSET..CHECKSUM_OFFSET + CHECKSUM_LEN],
+            checksum(&table).unwrap().to_le_bytes()
+        );
+
+        let mut flipped_checksum = bytes.clone();
+        flipped_checksum[CHECKSUM_OFFSET + 3] ^= 0x10;
+        std::fs::write(path, &flipped_checksum).unwrap();
+        match load(path) {
+            Err(EntriesFileError::Corrupt(err)) => {
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData)
+            }
+            _ => panic!("expected checksum mismatch"),
+        }
+
+        let mut flipped_data = bytes.clone();
+        let middle = HEADER_LEN + (bytes.len() - HEADER_LEN) / 2;
+        flipped_data[middle] ^= 0x10;
+        std::fs::write(path, &flipped_data).unwrap();
+        assert!(load(path).is_err());
+    }
+
+    #[test]
+    fn header_identifies_format() {
+        let cwd = std::env::current_dir().unwrap();
+        let fixture = Fixture::new("digest_header");
+        let path = &fixture.path("digest");
+        save(path, &FsIngester::new(&cwd).ingest()).unwrap();
+        let (header, _) = load_with_header(path).unwrap();
+        assert_eq!(header.version, FORMAT_VERSION);
+        assert_eq!(header.algorithm, DigestAlgorithm::Blake3);
+        assert_eq!(header.to_string(), "version 1, blake3 digests");
+
+        let bytes = std::fs::read(path).unwrap();
+        let patched = |offset: usize, value: u32| {
+            let mut bytes = bytes.clone();
+            bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+            std::fs::write(path, &bytes).unwrap();
+            load(path)
+        };
+        assert!(matches!(
+            patched(0, u32::from_le_bytes(*b"PK\x03\x04")),
+            Err(EntriesFileError::Corrupt(_))
+        ));
+        assert!(matches!(
+            patched(4, 2),
+            Err(EntriesFileError::UnsupportedVersion(2))
+        ));
+        assert!(matches!(
+            patched(8, 7),
+            Err(EntriesFileError::UnknownAlgorithm(7))
+        ));
+    }
+
+    #[test]
+    fn load_rejects_absurd_length_prefix() {
+        let fixture = Fixture::new("digest_absurd_length");
+        let path = &fixture.path("digest");
+        let write = |serialized_len: u64, bytes: &[u8]| {
+            let mut file_bytes = encode_header(0, serialized_len).to_vec();
+            file_bytes.extend(zstd::stream::encode_all(bytes, 0).unwrap());
+            std::fs::write(path, &file_bytes).unwrap();
+        };
+
+        // Decoding stops at the serialized length, before running out of data
+        let mut bytes = vec![0xffu8; 8]; // u64::MAX element count
+        bytes.extend_from_slice(&[0u8; 64]);
+        write(16, &bytes);
+        match load(path) {
+            Err(EntriesFileError::DataFormat(err)) => {
+                assert!(matches!(*err, bincode::ErrorKind::SizeLimit))
+            }
+            _ => panic!("expected the size limit to be hit"),
+        }
+
+        // The header can't claim far more data than the file holds
+        write(u64::MAX / 2, &bytes);
+        match load(path) {
+            Err(EntriesFileError::Corrupt(err)) => {
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData)
+            }
+            _ => panic!("expected an out of range length"),
+        }
+    }
+
+    #[test]
+    fn load_accepts_highly_compressible_data() {
+        let fixture = Fixture::new("digest_compressible");
+        let dir = &fixture.path("tree");
+        let path = &fixture.path("digest");
+        std::fs::create_dir_all(dir).unwrap();
+
+        // A table followed by trailing zeros, which compress to almost nothing
+        let mut bytes = bincode::serialize(&FsIngester::new(dir).ingest()).unwrap();
+        bytes.resize(bytes.len() + 4 * 1024 * 1024, 0);
+        let mut file_bytes = Hashing::new(io::sink());
+        file_bytes.write_all(&bytes).unwrap();
+        let mut file_bytes = encode_header(file_bytes.checksum(), bytes.len() as u64).to_vec();
+        file_bytes.extend(zstd::stream::encode_all(&bytes[..], 0).unwrap());
+        assert!(bytes.len() > 1024 * file_bytes.len());
+        std::fs::write(path, &file_bytes).unwrap();
+
+        assert_eq!(load(path).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn streaming_round_trip_matches_buffered() {
+        let cwd = std::env::current_dir().unwrap();
+        let fixture = Fixture::new("digest_streaming");
+        let path = &fixture.path("digest");
+        let table = FsIngester::new(&cwd).ingest();
+        save(path, &table).unwrap();
+
+        let streamed = load(path).unwrap();
+        let file_bytes = std::fs::read(path).unwrap();
+        let bytes = zstd::stream::decode_all(&file_bytes[HEADER_LEN..]).unwrap();
+        let buffered: FsTable = bincode::deserialize(&bytes).unwrap();
+
+        let expected = bincode::serialize(&table).unwrap();
+        assert_eq!(bytes, expected);
+        assert_eq!(bincode::serialize(&streamed).unwrap(), expected);
+        assert_eq!(bincode::serialize(&buffered).unwrap(), expected);
+    }
+}